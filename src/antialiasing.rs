@@ -0,0 +1,296 @@
+use wgpu::{BindGroup, BindGroupLayout, Buffer, CommandEncoder, Device, Queue, RenderPipeline, SurfaceConfiguration, TextureView};
+use wgpu::util::DeviceExt;
+
+use crate::texture::Texture;
+
+// Length of the jitter sequence, each pixel is sampled at this many positions before they repeat
+const JITTER_SAMPLES: u32 = 8;
+
+// Weight of the newest frame in the temporal history, smaller values smooth more but react slower
+const TAA_BLEND: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AntiAliasing {
+    None,
+    // Blurs along edges found in the tonemapped image, cheap but softens fine detail
+    Fxaa,
+    // Accumulates jittered frames over time, smooths edges and shading but trails behind fast motion
+    Taa,
+}
+
+pub struct Context {
+    pub mode: AntiAliasing,
+    uniform: TaaUniform,
+    buffer: Buffer,
+    // The main pass renders into this instead of the HDR target while temporal anti-aliasing is on
+    pub current: Texture,
+    // Resolved result of the previous frame
+    history: Texture,
+    taa_layout: BindGroupLayout,
+    taa_bind_group: BindGroup,
+    taa_pipeline: RenderPipeline,
+    // The tonemapped image, filtered into the surface while FXAA is on
+    pub ldr_texture: Texture,
+    fxaa_layout: BindGroupLayout,
+    fxaa_bind_group: BindGroup,
+    fxaa_pipeline: RenderPipeline,
+    // Position in the jitter sequence
+    frame: u32,
+    // Cleared whenever the history no longer matches the screen
+    history_valid: bool,
+}
+
+impl Context {
+    pub fn new(device: &Device, config: &SurfaceConfiguration, velocity: &Texture) -> Self {
+        let uniform = TaaUniform::default();
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("TAA Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        };
+        let taa_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture_entry(1),
+                texture_entry(2),
+                texture_entry(3),
+                sampler_entry(4),
+            ],
+            label: Some("taa_bind_group_layout"),
+        });
+        let fxaa_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[texture_entry(0), sampler_entry(1)],
+            label: Some("fxaa_bind_group_layout"),
+        });
+
+        let current = Texture::create_float_texture(device, config.width, config.height, "taa_current_texture");
+        let history = Texture::create_float_texture(device, config.width, config.height, "taa_history_texture");
+        let ldr_texture = Texture::create_target_texture(device, config.width, config.height, config.format, "fxaa_texture");
+        let taa_bind_group = Self::create_taa_bind_group(device, &taa_layout, &buffer, &current, &history, velocity);
+        let fxaa_bind_group = Self::create_fxaa_bind_group(device, &fxaa_layout, &ldr_texture);
+
+        let pipeline = |label, layout: &BindGroupLayout, source: &str, format: wgpu::TextureFormat| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(label),
+                bind_group_layouts: &[layout],
+                push_constant_ranges: &[],
+            });
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(label),
+                source: wgpu::ShaderSource::Wgsl(source.into()),
+            });
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(format.into())],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+        let taa_pipeline = pipeline("TAA Pipeline", &taa_layout, include_str!("taa.wgsl"), Texture::HDR_FORMAT);
+        let fxaa_pipeline = pipeline("FXAA Pipeline", &fxaa_layout, include_str!("fxaa.wgsl"), config.format);
+
+        Context {
+            mode: AntiAliasing::None,
+            uniform,
+            buffer,
+            current,
+            history,
+            taa_layout,
+            taa_bind_group,
+            taa_pipeline,
+            ldr_texture,
+            fxaa_layout,
+            fxaa_bind_group,
+            fxaa_pipeline,
+            frame: 0,
+            history_valid: false,
+        }
+    }
+
+    fn create_taa_bind_group(device: &Device, layout: &BindGroupLayout, buffer: &Buffer, current: &Texture, history: &Texture, velocity: &Texture) -> BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&current.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&history.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&velocity.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&current.sampler),
+                },
+            ],
+            label: Some("taa_bind_group"),
+        })
+    }
+
+    fn create_fxaa_bind_group(device: &Device, layout: &BindGroupLayout, source: &Texture) -> BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&source.sampler),
+                },
+            ],
+            label: Some("fxaa_bind_group"),
+        })
+    }
+
+    // Recreate the targets at the new surface size, the velocity buffer is recreated alongside them
+    pub fn resize(&mut self, device: &Device, config: &SurfaceConfiguration, velocity: &Texture) {
+        self.current = Texture::create_float_texture(device, config.width, config.height, "taa_current_texture");
+        self.history = Texture::create_float_texture(device, config.width, config.height, "taa_history_texture");
+        self.ldr_texture = Texture::create_target_texture(device, config.width, config.height, config.format, "fxaa_texture");
+        self.taa_bind_group = Self::create_taa_bind_group(device, &self.taa_layout, &self.buffer, &self.current, &self.history, velocity);
+        self.fxaa_bind_group = Self::create_fxaa_bind_group(device, &self.fxaa_layout, &self.ldr_texture);
+        self.history_valid = false;
+    }
+
+    // Start over from the next frame, for when it won't line up with the last one
+    pub fn reset_history(&mut self) {
+        self.history_valid = false;
+    }
+
+    pub fn set_mode(&mut self, mode: AntiAliasing) {
+        if mode != self.mode {
+            self.history_valid = false;
+        }
+        self.mode = mode;
+    }
+
+    // Offset of this frame's samples in normalized device coordinates, zero unless temporal anti-aliasing is on
+    pub fn jitter(&self, width: u32, height: u32) -> cgmath::Vector2<f32> {
+        if self.mode != AntiAliasing::Taa {
+            return cgmath::Vector2::new(0.0, 0.0);
+        }
+        // Halton points spread the samples evenly over the pixel, one pixel is 2 / size wide
+        let index = self.frame % JITTER_SAMPLES + 1;
+        cgmath::Vector2::new(
+            (halton(index, 2) - 0.5) * 2.0 / width as f32,
+            (halton(index, 3) - 0.5) * 2.0 / height as f32,
+        )
+    }
+
+    // Upload this frame's settings and move on in the jitter sequence
+    pub fn update(&mut self, queue: &Queue) {
+        if self.mode != AntiAliasing::Taa {
+            return;
+        }
+        self.uniform = TaaUniform {
+            blend: TAA_BLEND,
+            reset: !self.history_valid as u32,
+            ..self.uniform
+        };
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+        self.history_valid = true;
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    // Blend `current` into the history and write the result into `target`, which is kept as the next frame's history
+    pub fn resolve(&self, encoder: &mut CommandEncoder, target: &Texture, width: u32, height: u32) {
+        Self::pass(encoder, &target.view, &self.taa_pipeline, &self.taa_bind_group);
+        encoder.copy_texture_to_texture(
+            target.texture.as_image_copy(),
+            self.history.texture.as_image_copy(),
+            wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+        );
+    }
+
+    // Filter `ldr_texture` into the final target
+    pub fn fxaa(&self, encoder: &mut CommandEncoder, view: &TextureView) {
+        Self::pass(encoder, view, &self.fxaa_pipeline, &self.fxaa_bind_group);
+    }
+
+    fn pass(encoder: &mut CommandEncoder, target: &TextureView, pipeline: &RenderPipeline, bind_group: &BindGroup) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Anti-aliasing Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+// Radical inverse of `index` in the given base, a low discrepancy sequence in 0..1
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut result = 0.0;
+    let mut fraction = 1.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct TaaUniform {
+    pub blend: f32,
+    pub reset: u32,
+    pub _padding: [u32; 2],
+}
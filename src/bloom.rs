@@ -0,0 +1,251 @@
+use std::iter;
+
+use wgpu::{BindGroup, BindGroupLayout, Buffer, CommandEncoder, Device, Queue, RenderPipeline, SurfaceConfiguration, TextureView};
+use wgpu::util::DeviceExt;
+
+use crate::texture::Texture;
+
+// Most levels in the chain, each half the size of the previous one starting at half the screen
+const MAX_LEVELS: usize = 6;
+
+#[derive(Clone, Copy, Debug)]
+pub struct BloomSettings {
+    pub enabled: bool,
+    // Share of the blurred light added back onto the scene
+    pub intensity: f32,
+    // Brightness above which pixels bloom, 1.0 keeps everything the display can show without it
+    pub threshold: f32,
+    // Range below the threshold over which pixels gradually start blooming
+    pub knee: f32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        BloomSettings {
+            enabled: false,
+            intensity: 0.3,
+            threshold: 1.0,
+            knee: 0.5,
+        }
+    }
+}
+
+pub struct Context {
+    pub settings: BloomSettings,
+    buffer: Buffer,
+    layout: BindGroupLayout,
+    // Each level half the size of the previous one
+    levels: Vec<Texture>,
+    // Reading the HDR target followed by each level but the last
+    downsample_bind_groups: Vec<BindGroup>,
+    // Reading each level
+    upsample_bind_groups: Vec<BindGroup>,
+    prefilter_pipeline: RenderPipeline,
+    downsample_pipeline: RenderPipeline,
+    upsample_pipeline: RenderPipeline,
+    // Adds the first level onto the HDR target, scaled by the blend constant
+    composite_pipeline: RenderPipeline,
+}
+
+impl Context {
+    pub fn new(device: &Device, config: &SurfaceConfiguration, hdr_texture: &Texture, settings: BloomSettings) -> Self {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Bloom Buffer"),
+            contents: bytemuck::cast_slice(&[BloomUniform::from(settings)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("bloom_bind_group_layout"),
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Bloom Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Bloom Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("bloom.wgsl").into()),
+        });
+        // Upsampling adds onto the target, the composite scales what it adds by the blend constant
+        let additive = |src_factor| wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::Zero,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+        };
+        let pipeline = |entry_point, blend: Option<wgpu::BlendState>| device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Bloom Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point,
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: Texture::HDR_FORMAT,
+                    blend,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        let mut context = Context {
+            settings,
+            buffer,
+            levels: Vec::new(),
+            downsample_bind_groups: Vec::new(),
+            upsample_bind_groups: Vec::new(),
+            prefilter_pipeline: pipeline("fs_prefilter", None),
+            downsample_pipeline: pipeline("fs_downsample", None),
+            upsample_pipeline: pipeline("fs_upsample", Some(additive(wgpu::BlendFactor::One))),
+            composite_pipeline: pipeline("fs_upsample", Some(additive(wgpu::BlendFactor::Constant))),
+            layout,
+        };
+        context.resize(device, config, hdr_texture);
+        context
+    }
+
+    fn create_bind_group(&self, device: &Device, source: &Texture) -> BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&source.sampler),
+                },
+            ],
+            label: Some("bloom_bind_group"),
+        })
+    }
+
+    // Rebuild the chain for the new surface size, it stops early once a level would be a single texel wide
+    pub fn resize(&mut self, device: &Device, config: &SurfaceConfiguration, hdr_texture: &Texture) {
+        let (mut width, mut height) = (config.width, config.height);
+        self.levels.clear();
+        while self.levels.len() < MAX_LEVELS && width > 1 && height > 1 {
+            width /= 2;
+            height /= 2;
+            self.levels.push(Texture::create_float_texture(device, width, height, "bloom_texture"));
+        }
+
+        let sources = iter::once(hdr_texture).chain(self.levels.iter().take(self.levels.len().saturating_sub(1)));
+        self.downsample_bind_groups = sources.map(|source| self.create_bind_group(device, source)).collect();
+        self.upsample_bind_groups = self.levels.iter().map(|level| self.create_bind_group(device, level)).collect();
+    }
+
+    pub fn set_settings(&mut self, queue: &Queue, mut settings: BloomSettings) {
+        settings.intensity = settings.intensity.max(0.0);
+        settings.threshold = settings.threshold.max(0.0);
+        settings.knee = settings.knee.max(0.0);
+        self.settings = settings;
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[BloomUniform::from(settings)]));
+    }
+
+    // Blur the bright parts of the HDR target down the chain and back up, then add them onto it
+    pub fn draw(&self, encoder: &mut CommandEncoder, hdr_texture: &Texture) {
+        if !self.settings.enabled || self.levels.is_empty() {
+            return;
+        }
+
+        for (index, (level, bind_group)) in self.levels.iter().zip(&self.downsample_bind_groups).enumerate() {
+            let pipeline = if index == 0 { &self.prefilter_pipeline } else { &self.downsample_pipeline };
+            Self::pass(encoder, &level.view, wgpu::LoadOp::Clear(wgpu::Color::BLACK), pipeline, bind_group, None);
+        }
+        for index in (1..self.levels.len()).rev() {
+            Self::pass(encoder, &self.levels[index - 1].view, wgpu::LoadOp::Load, &self.upsample_pipeline, &self.upsample_bind_groups[index], None);
+        }
+        let intensity = self.settings.intensity as f64;
+        let constant = wgpu::Color { r: intensity, g: intensity, b: intensity, a: intensity };
+        Self::pass(encoder, &hdr_texture.view, wgpu::LoadOp::Load, &self.composite_pipeline, &self.upsample_bind_groups[0], Some(constant));
+    }
+
+    fn pass(encoder: &mut CommandEncoder, target: &TextureView, load: wgpu::LoadOp<wgpu::Color>, pipeline: &RenderPipeline, bind_group: &BindGroup, blend_constant: Option<wgpu::Color>) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Bloom Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        if let Some(constant) = blend_constant {
+            pass.set_blend_constant(constant);
+        }
+        pass.draw(0..3, 0..1);
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct BloomUniform {
+    pub threshold: f32,
+    pub knee: f32,
+    pub _padding: [f32; 2],
+}
+
+impl From<BloomSettings> for BloomUniform {
+    fn from(settings: BloomSettings) -> Self {
+        BloomUniform {
+            threshold: settings.threshold,
+            knee: settings.knee,
+            _padding: [0.0; 2],
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// Vertex shader
+
+// Spreads the bright parts of the HDR scene over a chain of ever smaller textures, then adds them back up
+// into the scene, following the downsample and upsample filters from Call of Duty: Advanced Warfare
+
+// Matches `BloomUniform` in bloom.rs
+struct Bloom {
+    // Brightness where the bloom starts, reached gradually over `knee` below it
+    threshold: f32,
+    knee: f32,
+}
+@group(0) @binding(0)
+var<uniform> bloom: Bloom;
+@group(0) @binding(1)
+var t_source: texture_2d<f32>;
+@group(0) @binding(2)
+var s_source: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let position = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u)) * 2.0 - 1.0;
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(position, 0.0, 1.0);
+    out.uv = position * vec2<f32>(0.5, -0.5) + 0.5;
+    return out;
+}
+
+// Fragment shader
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn source(uv: vec2<f32>, texel: vec2<f32>, x: f32, y: f32) -> vec3<f32> {
+    return textureSampleLevel(t_source, s_source, uv + texel * vec2<f32>(x, y), 0.0).rgb;
+}
+
+// Weighs a group of samples by their brightness so single very bright pixels don't flicker as they move
+fn karis_average(a: vec3<f32>, b: vec3<f32>, c: vec3<f32>, d: vec3<f32>) -> vec3<f32> {
+    let wa = 1.0 / (1.0 + luminance(a));
+    let wb = 1.0 / (1.0 + luminance(b));
+    let wc = 1.0 / (1.0 + luminance(c));
+    let wd = 1.0 / (1.0 + luminance(d));
+    return (a * wa + b * wb + c * wc + d * wd) / (wa + wb + wc + wd);
+}
+
+// Keep the part of the color above the threshold, with a quadratic curve through the knee
+fn prefilter(color: vec3<f32>) -> vec3<f32> {
+    let brightness = max(color.r, max(color.g, color.b));
+    let knee = max(bloom.knee, 0.0001);
+    var soft = clamp(brightness - bloom.threshold + knee, 0.0, 2.0 * knee);
+    soft = soft * soft / (4.0 * knee);
+    let contribution = max(soft, brightness - bloom.threshold) / max(brightness, 0.0001);
+    return color * contribution;
+}
+
+// 13 samples of the previous level, arranged as five overlapping 2x2 boxes
+fn downsample(uv: vec2<f32>, first: bool) -> vec3<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(t_source));
+    let a = source(uv, texel, -2.0, -2.0);
+    let b = source(uv, texel, 0.0, -2.0);
+    let c = source(uv, texel, 2.0, -2.0);
+    let d = source(uv, texel, -1.0, -1.0);
+    let e = source(uv, texel, 1.0, -1.0);
+    let f = source(uv, texel, -2.0, 0.0);
+    let g = source(uv, texel, 0.0, 0.0);
+    let h = source(uv, texel, 2.0, 0.0);
+    let i = source(uv, texel, -1.0, 1.0);
+    let j = source(uv, texel, 1.0, 1.0);
+    let k = source(uv, texel, -2.0, 2.0);
+    let l = source(uv, texel, 0.0, 2.0);
+    let m = source(uv, texel, 2.0, 2.0);
+
+    if (first) {
+        return karis_average(d, e, i, j) * 0.5
+            + karis_average(a, b, f, g) * 0.125
+            + karis_average(b, c, g, h) * 0.125
+            + karis_average(f, g, k, l) * 0.125
+            + karis_average(g, h, l, m) * 0.125;
+    }
+    return (d + e + i + j) * 0.125
+        + (a + c + k + m) * 0.03125
+        + (b + f + h + l) * 0.0625
+        + g * 0.125;
+}
+
+// From the HDR scene into the largest level, only keeping what is bright enough to bloom
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(prefilter(downsample(in.uv, true)), 1.0);
+}
+
+@fragment
+fn fs_downsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(downsample(in.uv, false), 1.0);
+}
+
+// 3x3 tent filter over the smaller level, added onto the larger one by blending
+@fragment
+fn fs_upsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(t_source));
+    var color = source(in.uv, texel, 0.0, 0.0) * 4.0;
+    color = color + (source(in.uv, texel, -1.0, 0.0) + source(in.uv, texel, 1.0, 0.0) + source(in.uv, texel, 0.0, -1.0) + source(in.uv, texel, 0.0, 1.0)) * 2.0;
+    color = color + source(in.uv, texel, -1.0, -1.0) + source(in.uv, texel, 1.0, -1.0) + source(in.uv, texel, -1.0, 1.0) + source(in.uv, texel, 1.0, 1.0);
+    return vec4<f32>(color / 16.0, 1.0);
+}
@@ -8,11 +8,14 @@ pub struct Context {
     pub uniform: CameraUniform,
     pub buffer: Buffer,
     pub bind_group: BindGroup,
+    // Unjittered view projection of the last rendered frame, for working out how far things moved on screen
+    pub previous_view_proj: cgmath::Matrix4<f32>,
 }
 
 impl Context {
     pub fn new(c: Camera, cc: CameraController, cu: CameraUniform, cb: Buffer, cbg: BindGroup) -> Self {
         Context {
+            previous_view_proj: c.unjittered_view_projection_matrix(),
             camera: c,
             controller: cc,
             uniform: cu,
@@ -30,10 +33,19 @@ pub(crate) struct Camera {
     pub fovy: f32,
     pub znear: f32,
     pub zfar: f32,
+    // Offset of the whole image in normalized device coordinates, moved by a fraction of a pixel
+    // every frame so temporal anti-aliasing sees a different sample position each time
+    pub jitter: cgmath::Vector2<f32>,
 }
 
 impl Camera {
     pub fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
+        // Translating in clip space scales the offset with w, so it stays the same after the perspective divide
+        let jitter = cgmath::Matrix4::from_translation(cgmath::Vector3::new(self.jitter.x, self.jitter.y, 0.0));
+        jitter * self.unjittered_view_projection_matrix()
+    }
+
+    pub fn unjittered_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
         let view = cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up);
         let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
         proj * view
@@ -45,6 +57,12 @@ impl Camera {
 pub(crate) struct CameraUniform {
     view_position: [f32; 4],
     view_proj: [[f32; 4]; 4],
+    // Turns screen positions back into world space, for drawing the sky
+    inv_view_proj: [[f32; 4]; 4],
+    // Unjittered view projection of the previous frame, for the velocity buffer
+    pub prev_view_proj: [[f32; 4]; 4],
+    // Current jitter in xy, subtracted again when comparing against the previous frame
+    jitter: [f32; 4],
 }
 
 impl CameraUniform {
@@ -52,13 +70,19 @@ impl CameraUniform {
         Self {
             view_position: [0.0; 4],
             view_proj: cgmath::Matrix4::identity().into(),
+            inv_view_proj: cgmath::Matrix4::identity().into(),
+            prev_view_proj: cgmath::Matrix4::identity().into(),
+            jitter: [0.0; 4],
         }
     }
 
     pub fn update_view_proj(&mut self, camera: &Camera) {
         // We're using Vector4 because ofthe camera_uniform 16 byte spacing requirement
         self.view_position = camera.eye.to_homogeneous().into();
-        self.view_proj = camera.build_view_projection_matrix().into();
+        let view_proj = camera.build_view_projection_matrix();
+        self.view_proj = view_proj.into();
+        self.inv_view_proj = view_proj.invert().unwrap_or(cgmath::Matrix4::identity()).into();
+        self.jitter = [camera.jitter.x, camera.jitter.y, 0.0, 0.0];
     }
 }
 
@@ -0,0 +1,11 @@
+// Shifts red outwards and blue inwards, more towards the edges of the screen. params[0].x: strength
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let strength = post.params[0].x;
+    let offset = (in.uv - 0.5) * strength;
+    let center = scene_color(in.uv);
+    let red = scene_color(in.uv + offset).r;
+    let blue = scene_color(in.uv - offset).b;
+    return vec4<f32>(red, center.g, blue, center.a);
+}
@@ -0,0 +1,14 @@
+// Looks display colors up in a 3D table. params[0]: intensity and the table size
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = scene_color(in.uv);
+    let intensity = post.params[0].x;
+    let size = post.params[0].y;
+
+    // The outer texels hold the table's end points, so coordinates are pulled in by half a texel
+    let display = clamp(to_display(color.rgb), vec3<f32>(0.0), vec3<f32>(1.0));
+    let coordinates = display * (size - 1.0) / size + 0.5 / size;
+    let graded = from_display(textureSampleLevel(t_lut, s_linear, coordinates, 0.0).rgb);
+    return vec4<f32>(mix(color.rgb, graded, intensity), color.a);
+}
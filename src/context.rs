@@ -1,16 +1,27 @@
-use wgpu::{Surface, Device, Queue, SurfaceConfiguration, BindGroup, BindGroupLayout, BindGroupDescriptor, BindGroupEntry, BindingResource};
+use wgpu::{Device, Queue, SurfaceConfiguration, BindGroup, BindGroupLayout, BindGroupDescriptor, BindGroupEntry, BindingResource, Buffer};
+use std::cell::RefCell;
 use std::io::{BufReader, Cursor};
+use std::path::Path;
+use std::rc::Rc;
+
+use anyhow::anyhow;
+use cgmath::{InnerSpace, Matrix, Matrix4, SquareMatrix};
 
 
 use cfg_if::cfg_if;
 
 use crate::texture;
+use crate::mipmap;
 
-use crate::texture::Texture;
+use crate::texture::{Texture, TextureOptions};
 
+use crate::model;
 use crate::model::Model;
+use crate::model::Mesh;
 use crate::model::Material;
-use crate::model::Area3D;
+use crate::model::MaterialTextures;
+use crate::model::MaterialUniform;
+use crate::model::ModelVertex;
 
 pub struct Context {
     pub device: Device,
@@ -18,11 +29,20 @@ pub struct Context {
     pub layout: BindGroupLayout,
     pub models: Vec<Model>,
     pub config: SurfaceConfiguration,
+    // Bound in place of the textures a material doesn't have
+    pub white_texture: Texture,
+    pub flat_normal_texture: Texture,
+    // Renders the mip chains of loaded textures, they are built on the CPU without it
+    pub mipmaps: Option<mipmap::Generator>,
+    // Whether samplers can filter anisotropically, `SamplerOptions::anisotropy` is dropped without it
+    pub anisotropic_filtering: bool,
 }
 
 impl Context {
-    pub fn new(device: Device, queue: Queue, layout: BindGroupLayout, config: SurfaceConfiguration) -> Self {
-        Context { device, queue, layout, config, models: vec![] }
+    pub fn new(device: Device, queue: Queue, layout: BindGroupLayout, config: SurfaceConfiguration, mipmaps: Option<mipmap::Generator>, anisotropic_filtering: bool) -> Self {
+        let white_texture = Texture::from_color(&device, &queue, [1.0; 4], "white_texture").unwrap();
+        let flat_normal_texture = Texture::flat_normal(&device, &queue, "flat_normal_texture").unwrap();
+        Context { device, queue, layout, config, models: vec![], white_texture, flat_normal_texture, mipmaps, anisotropic_filtering }
     }
     #[cfg(target_arch = "wasm32")]
     fn format_url(file_name: &str) -> reqwest::Url {
@@ -79,16 +99,39 @@ impl Context {
         Ok(data)
     }
 
-    pub async fn load_texture(&mut self, file_name: &str) -> anyhow::Result<texture::Texture> {
+    pub async fn load_texture(&mut self, file_name: &str, linear: bool, options: TextureOptions) -> anyhow::Result<texture::Texture> {
         let data = Self::load_binary(file_name).await?;
-        texture::Texture::from_bytes(&self.device, &self.queue, &data, file_name)
+        let options = self.supported_options(options);
+        texture::Texture::from_bytes(&self.device, &self.queue, &data, file_name, linear, options, self.mipmaps.as_ref())
     }
 
-    pub async fn load_model(&mut self, file_name: &str, area: Area3D) -> Result<(), anyhow::Error> {
+    // Load a texture a material refers to, a missing name or a file that fails to load leaves it out
+    pub async fn load_material_texture(&mut self, file_name: &str, linear: bool, options: TextureOptions) -> Option<texture::Texture> {
+        if file_name.is_empty() {
+            return None;
+        }
+        match self.load_texture(file_name, linear, options).await {
+            Ok(texture) => Some(texture),
+            Err(e) => {
+                log::warn!("Failed to load texture {}: {}", file_name, e);
+                None
+            }
+        }
+    }
+
+    // `options` apply to every texture of the model, where its materials don't override them
+    pub async fn load_model(&mut self, file_name: &str, options: TextureOptions) -> Result<(), anyhow::Error> {
+        let extension = Path::new(file_name).extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        if matches!(extension.as_deref(), Some("gltf" | "glb")) {
+            return self.load_gltf(file_name, options).await;
+        }
+
         let obj_text = Self::load_string(file_name).await?;
         let obj_cursor = Cursor::new(obj_text);
         let mut obj_reader = BufReader::new(obj_cursor);
 
+        // tobj reports the colors a material leaves out as black, the text tells which ones it sets
+        let mtl_texts = Rc::new(RefCell::new(Vec::new()));
         let (models, obj_materials) = tobj::load_obj_buf_async(
             &mut obj_reader,
             &tobj::LoadOptions {
@@ -96,26 +139,250 @@ impl Context {
                 single_index: true,
                 ..Default::default()
             },
-            |p| async move {
-                let mat_text = Self::load_string(&p).await.unwrap();
-                tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
+            |p| {
+                let mtl_texts = mtl_texts.clone();
+                async move {
+                    let mat_text = Self::load_string(&p).await.map_err(|_| tobj::LoadError::OpenFileFailed)?;
+                    let result = tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(&mat_text)));
+                    mtl_texts.borrow_mut().push(mat_text);
+                    result
+                }
             },
         )
         .await?;
+        let declared_keys = model::mtl_keys(&mtl_texts.borrow());
+
+        // A missing material library only costs the model its colors
+        let obj_materials = obj_materials.unwrap_or_else(|e| {
+            log::warn!("Failed to load materials for {}: {}", file_name, e);
+            Vec::new()
+        });
 
         let mut materials = Vec::new();
-        for m in obj_materials? {
-            let material = Material::new(self, file_name.to_string(), m).await;
+        for m in obj_materials {
+            let keys = declared_keys.get(&m.name).cloned().unwrap_or_default();
+            let material = Material::new(self, file_name.to_string(), m, &keys, options).await;
             materials.push(material);
         }
 
-        let model = Model::new(self, area, models, materials, file_name.to_string());
+        // Meshes without a material are drawn plain white
+        materials.push(Material::from_textures(self, file_name.to_string(), MaterialTextures::default(), MaterialUniform::default()));
+
+        let model = Model::new(self, models, materials, file_name.to_string());
         self.models.push(model);
 
         Ok(())
     }
 
-    pub fn create_bind_group(&mut self, diffuse_texture: &Texture) -> BindGroup {
+    pub async fn load_gltf(&mut self, file_name: &str, options: TextureOptions) -> Result<(), anyhow::Error> {
+        let data = Self::load_binary(file_name).await?;
+        let gltf = gltf::Gltf::from_slice(&data)?;
+
+        let mut buffers = Vec::new();
+        for buffer in gltf.buffers() {
+            let data = match buffer.source() {
+                gltf::buffer::Source::Bin => gltf.blob.clone().ok_or_else(|| anyhow!("{} has no binary chunk", file_name))?,
+                gltf::buffer::Source::Uri(uri) => Self::load_uri(file_name, uri).await?,
+            };
+            buffers.push(data);
+        }
+
+        let mut images = Vec::new();
+        for img in gltf.images() {
+            let data = match img.source() {
+                gltf::image::Source::View { view, .. } => {
+                    let range = view.offset()..view.offset() + view.length();
+                    buffers[view.buffer().index()].get(range).ok_or_else(|| anyhow!("{} has an image view past the end of its buffer", file_name))?.to_vec()
+                }
+                gltf::image::Source::Uri { uri, .. } => Self::load_uri(file_name, uri).await?,
+            };
+            images.push(image::load_from_memory(&data)?);
+        }
+
+        let mut materials = Vec::new();
+        for m in gltf.materials() {
+            let name = m.name().map(str::to_string).unwrap_or_else(|| file_name.to_string());
+            let pbr = m.pbr_metallic_roughness();
+            let texture = |texture: gltf::Texture, linear: bool| {
+                let options = self.supported_options(Self::gltf_texture_options(&texture.sampler(), options));
+                Texture::from_image(&self.device, &self.queue, &images[texture.source().index()], Some(&name), linear, options, self.mipmaps.as_ref())
+            };
+            let textures = MaterialTextures {
+                diffuse: pbr.base_color_texture().map(|info| texture(info.texture(), false)).transpose()?,
+                normal: m.normal_texture().map(|info| texture(info.texture(), true)).transpose()?,
+                metallic_roughness: pbr.metallic_roughness_texture().map(|info| texture(info.texture(), true)).transpose()?,
+                occlusion: m.occlusion_texture().map(|info| texture(info.texture(), true)).transpose()?,
+                emissive: m.emissive_texture().map(|info| texture(info.texture(), false)).transpose()?,
+            };
+            // The factors multiply the textures, the base color stands in for an MTL material's diffuse color
+            let [r, g, b, a] = pbr.base_color_factor();
+            let [er, eg, eb] = m.emissive_factor();
+            let alpha_mode = match m.alpha_mode() {
+                gltf::material::AlphaMode::Opaque => MaterialUniform::ALPHA_OPAQUE,
+                gltf::material::AlphaMode::Mask => MaterialUniform::ALPHA_MASK,
+                gltf::material::AlphaMode::Blend => MaterialUniform::ALPHA_BLEND,
+            };
+            let uniform = MaterialUniform {
+                diffuse: [r, g, b],
+                // Opaque materials render fully opaque whatever their base color's alpha
+                dissolve: if alpha_mode == MaterialUniform::ALPHA_OPAQUE { 1.0 } else { a },
+                alpha_mode,
+                alpha_cutoff: m.alpha_cutoff().unwrap_or(0.5),
+                shading: MaterialUniform::SHADING_PBR,
+                emissive: [er, eg, eb],
+                metallic: pbr.metallic_factor(),
+                roughness: pbr.roughness_factor(),
+                occlusion_strength: m.occlusion_texture().map_or(1.0, |info| info.strength()),
+                ..MaterialUniform::default()
+            };
+            materials.push(Material::from_textures(self, name, textures, uniform));
+        }
+
+        // Primitives without a material use the glTF default material, a plain white rough metal
+        let default_material = materials.len();
+        let uniform = MaterialUniform { shading: MaterialUniform::SHADING_PBR, metallic: 1.0, roughness: 1.0, alpha_mode: MaterialUniform::ALPHA_OPAQUE, ..MaterialUniform::default() };
+        materials.push(Material::from_textures(self, file_name.to_string(), MaterialTextures::default(), uniform));
+
+        let scene = gltf.default_scene().or_else(|| gltf.scenes().next()).ok_or_else(|| anyhow!("{} has no scene", file_name))?;
+        let mut meshes = Vec::new();
+        for node in scene.nodes() {
+            self.load_gltf_node(file_name, &node, Matrix4::identity(), &buffers, default_material, &mut meshes);
+        }
+
+        let model = Model::from_meshes(meshes, materials);
+        self.models.push(model);
+
+        Ok(())
+    }
+
+    // Drop what the adapter can't do from the options a texture is created with
+    fn supported_options(&self, mut options: TextureOptions) -> TextureOptions {
+        if !self.anisotropic_filtering {
+            options.sampler.anisotropy = 1;
+        }
+        options
+    }
+
+    // Override the defaults with what a glTF sampler sets, a minification filter without a mipmap
+    // mode asks for the texture to go without them
+    fn gltf_texture_options(sampler: &gltf::texture::Sampler, mut options: TextureOptions) -> TextureOptions {
+        use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+        use texture::{AddressMode, FilterMode};
+
+        let address_mode = |mode| match mode {
+            WrappingMode::ClampToEdge => AddressMode::ClampToEdge,
+            WrappingMode::MirroredRepeat => AddressMode::MirrorRepeat,
+            WrappingMode::Repeat => AddressMode::Repeat,
+        };
+        options.sampler.address_mode_u = address_mode(sampler.wrap_s());
+        options.sampler.address_mode_v = address_mode(sampler.wrap_t());
+        match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => options.sampler.mag_filter = FilterMode::Nearest,
+            Some(MagFilter::Linear) => options.sampler.mag_filter = FilterMode::Linear,
+            None => {}
+        }
+        let (min_filter, mipmap_filter) = match sampler.min_filter() {
+            Some(MinFilter::Nearest) => (FilterMode::Nearest, None),
+            Some(MinFilter::Linear) => (FilterMode::Linear, None),
+            Some(MinFilter::NearestMipmapNearest) => (FilterMode::Nearest, Some(FilterMode::Nearest)),
+            Some(MinFilter::LinearMipmapNearest) => (FilterMode::Linear, Some(FilterMode::Nearest)),
+            Some(MinFilter::NearestMipmapLinear) => (FilterMode::Nearest, Some(FilterMode::Linear)),
+            Some(MinFilter::LinearMipmapLinear) => (FilterMode::Linear, Some(FilterMode::Linear)),
+            None => return options,
+        };
+        options.sampler.min_filter = min_filter;
+        match mipmap_filter {
+            Some(filter) => options.sampler.mipmap_filter = filter,
+            None => options.mipmaps = false,
+        }
+        options
+    }
+
+    // Walk the node hierarchy, baking each node's accumulated transform into its vertices
+    fn load_gltf_node(&mut self, file_name: &str, node: &gltf::Node, parent: Matrix4<f32>, buffers: &[Vec<u8>], default_material: usize, meshes: &mut Vec<Mesh>) {
+        let transform = parent * Matrix4::from(node.transform().matrix());
+
+        if let Some(mesh) = node.mesh() {
+            let normal_matrix = transform.invert().unwrap_or(transform).transpose();
+            // Mirroring transforms flip the triangle winding, which would get culled as back faces
+            let mirrored = transform.determinant() < 0.0;
+
+            for primitive in mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    log::warn!("Skipping non-triangle primitive in {}", file_name);
+                    continue;
+                }
+
+                let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+                let Some(positions) = reader.read_positions().map(|p| p.collect::<Vec<_>>()) else { continue };
+                let mut indices = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+                    None => (0..positions.len() as u32).collect(),
+                };
+                let normals = match reader.read_normals() {
+                    Some(normals) => normals.collect::<Vec<_>>(),
+                    None => model::compute_normals(&positions, &indices),
+                };
+                let tex_coords = reader.read_tex_coords(0).map(|t| t.into_f32().collect::<Vec<_>>());
+                let tangents = reader.read_tangents().map(|t| t.collect::<Vec<_>>());
+
+                if mirrored {
+                    indices.chunks_exact_mut(3).for_each(|t| t.swap(1, 2));
+                }
+
+                let mut vertices = (0..positions.len()).map(|i| {
+                    let [x, y, z] = positions[i];
+                    let normal = cgmath::Vector3::from(normals[i]);
+                    // Tangents follow the surface, so they take the node transform rather than the normal matrix
+                    let (tangent, bitangent) = tangents.as_ref().map_or(([0.0; 3], [0.0; 3]), |t| {
+                        let [tx, ty, tz, w] = t[i];
+                        let bitangent = normal.cross(cgmath::Vector3::new(tx, ty, tz)) * w;
+                        (
+                            (transform * cgmath::Vector4::new(tx, ty, tz, 0.0)).truncate().normalize().into(),
+                            (transform * bitangent.extend(0.0)).truncate().normalize().into(),
+                        )
+                    });
+                    ModelVertex {
+                        position: (transform * cgmath::Vector4::new(x, y, z, 1.0)).truncate().into(),
+                        tex_coords: tex_coords.as_ref().map_or([0.0, 0.0], |t| t[i]),
+                        normal: (normal_matrix * normal.extend(0.0)).truncate().normalize().into(),
+                        tangent,
+                        bitangent,
+                    }
+                }).collect::<Vec<_>>();
+                if tangents.is_none() {
+                    model::compute_tangents(&mut vertices, &indices);
+                }
+
+                let name = mesh.name().unwrap_or(file_name).to_string();
+                let material = primitive.material().index().unwrap_or(default_material);
+                meshes.push(Mesh::from_vertices(self, name, &vertices, &indices, material));
+            }
+        }
+
+        for child in node.children() {
+            self.load_gltf_node(file_name, &child, transform, buffers, default_material, meshes);
+        }
+    }
+
+    // Resolve a glTF uri, either an embedded base64 data uri or a file relative to the document
+    async fn load_uri(file_name: &str, uri: &str) -> anyhow::Result<Vec<u8>> {
+        if let Some(data) = uri.strip_prefix("data:") {
+            let (_, encoded) = data.split_once(";base64,").ok_or_else(|| anyhow!("Unsupported data uri in {}", file_name))?;
+            return Ok(base64::decode(encoded)?);
+        }
+
+        let path = Path::new(file_name).parent().unwrap_or_else(|| Path::new("")).join(uri);
+        Self::load_binary(&path.to_string_lossy()).await
+    }
+
+    pub fn create_bind_group(&mut self, textures: &MaterialTextures, uniform: &Buffer) -> BindGroup {
+        let white = &self.white_texture;
+        let diffuse_texture = textures.diffuse.as_ref().unwrap_or(white);
+        let normal_texture = textures.normal.as_ref().unwrap_or(&self.flat_normal_texture);
+        let metallic_roughness_texture = textures.metallic_roughness.as_ref().unwrap_or(white);
+        let occlusion_texture = textures.occlusion.as_ref().unwrap_or(white);
+        let emissive_texture = textures.emissive.as_ref().unwrap_or(white);
         self.device.create_bind_group(&BindGroupDescriptor {
             layout: &self.layout,
             entries: &[
@@ -127,6 +394,42 @@ impl Context {
                     binding: 1,
                     resource: BindingResource::Sampler(&diffuse_texture.sampler),
                 },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(&normal_texture.view),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::Sampler(&normal_texture.sampler),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: uniform.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: BindingResource::TextureView(&metallic_roughness_texture.view),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: BindingResource::Sampler(&metallic_roughness_texture.sampler),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: BindingResource::TextureView(&occlusion_texture.view),
+                },
+                BindGroupEntry {
+                    binding: 8,
+                    resource: BindingResource::Sampler(&occlusion_texture.sampler),
+                },
+                BindGroupEntry {
+                    binding: 9,
+                    resource: BindingResource::TextureView(&emissive_texture.view),
+                },
+                BindGroupEntry {
+                    binding: 10,
+                    resource: BindingResource::Sampler(&emissive_texture.sampler),
+                },
             ],
             label: None,
         })
@@ -0,0 +1,420 @@
+use wgpu::{BindGroup, BindGroupLayout, Buffer, Device, Queue, RenderPipeline};
+use wgpu::util::DeviceExt;
+
+use crate::texture::Texture;
+
+// Width of each face of the environment cube map, the sky seen in reflections of smooth surfaces
+const CUBE_SIZE: u32 = 256;
+// Diffuse lighting only changes slowly with the surface direction
+const IRRADIANCE_SIZE: u32 = 32;
+const PREFILTERED_SIZE: u32 = 128;
+// Roughness 0, 0.25, 0.5, 0.75 and 1, the main shader blends between neighbouring levels
+const PREFILTERED_MIPS: u32 = 5;
+const BRDF_LUT_SIZE: u32 = 128;
+
+pub struct Context {
+    pub uniform: EnvironmentUniform,
+    pub buffer: Buffer,
+    // Radiance in every direction, converted from the equirectangular image
+    pub cube: Texture,
+    // Cosine weighted light arriving from each hemisphere, for diffuse lighting
+    pub irradiance: Texture,
+    // Reflected light for increasing roughness down the mip chain, for specular lighting
+    pub prefiltered: Texture,
+    // Split sum scale and bias for F0, only depends on the BRDF so it is computed once
+    pub brdf_lut: Texture,
+    // The camera uniform shares this bind group, every other group index is taken
+    pub layout: BindGroupLayout,
+    equirectangular_layout: BindGroupLayout,
+    cube_layout: BindGroupLayout,
+    equirectangular_pipeline: RenderPipeline,
+    irradiance_pipeline: RenderPipeline,
+    prefilter_pipeline: RenderPipeline,
+}
+
+impl Context {
+    pub fn new(device: &Device, queue: &Queue) -> Self {
+        let uniform = EnvironmentUniform::new();
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let cube_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::Cube,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                // Camera
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                cube_entry(2),
+                cube_entry(3),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                // Screen-space ambient occlusion, darkening the ambient light next to the environment's
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    },
+                    count: None,
+                },
+                // Fog, faded in over the lit surfaces by distance and height
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("camera_bind_group_layout"),
+        });
+
+        // The precomputation passes read the previous result through binding 1 and 2
+        let pass_layout = |label, view_dimension, filterable| {
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension,
+                            sample_type: wgpu::TextureSampleType::Float { filterable },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(if filterable {
+                            wgpu::SamplerBindingType::Filtering
+                        } else {
+                            wgpu::SamplerBindingType::NonFiltering
+                        }),
+                        count: None,
+                    },
+                ],
+                label: Some(label),
+            })
+        };
+        let equirectangular_layout = pass_layout("equirectangular_bind_group_layout", wgpu::TextureViewDimension::D2, false);
+        let cube_layout = pass_layout("environment_cube_bind_group_layout", wgpu::TextureViewDimension::Cube, true);
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Environment Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("environment.wgsl").into()),
+        });
+        let create_pipeline = |label: &str, layout: Option<&BindGroupLayout>, entry_point: &str| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(label),
+                bind_group_layouts: &layout.into_iter().collect::<Vec<_>>(),
+                push_constant_ranges: &[],
+            });
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point,
+                    targets: &[Some(Texture::HDR_FORMAT.into())],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+        let equirectangular_pipeline = create_pipeline("Equirectangular Pipeline", Some(&equirectangular_layout), "fs_equirectangular");
+        let irradiance_pipeline = create_pipeline("Irradiance Pipeline", Some(&cube_layout), "fs_irradiance");
+        let prefilter_pipeline = create_pipeline("Prefilter Pipeline", Some(&cube_layout), "fs_prefilter");
+        let brdf_pipeline = create_pipeline("BRDF Pipeline", None, "fs_brdf");
+
+        let cube = Texture::create_cube_texture(device, CUBE_SIZE, CUBE_SIZE.ilog2() + 1, "environment_texture");
+        let irradiance = Texture::create_cube_texture(device, IRRADIANCE_SIZE, 1, "irradiance_texture");
+        let prefiltered = Texture::create_cube_texture(device, PREFILTERED_SIZE, PREFILTERED_MIPS, "prefiltered_texture");
+        let brdf_lut = Texture::create_float_texture(device, BRDF_LUT_SIZE, BRDF_LUT_SIZE, "brdf_lut_texture");
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("BRDF Encoder") });
+        {
+            let view = brdf_lut.texture.create_view(&wgpu::TextureViewDescriptor::default());
+            let mut pass = Self::begin_pass(&mut encoder, &view);
+            pass.set_pipeline(&brdf_pipeline);
+            pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+
+        Context {
+            uniform,
+            buffer,
+            cube,
+            irradiance,
+            prefiltered,
+            brdf_lut,
+            layout,
+            equirectangular_layout,
+            cube_layout,
+            equirectangular_pipeline,
+            irradiance_pipeline,
+            prefilter_pipeline,
+        }
+    }
+
+    // Bind group for the camera together with the environment maps, ambient occlusion and fog
+    pub fn create_bind_group(&self, device: &Device, camera_buffer: &Buffer, occlusion: &Texture, fog_buffer: &Buffer) -> BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: camera_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: self.buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&self.irradiance.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&self.prefiltered.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&self.brdf_lut.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(&self.prefiltered.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(&occlusion.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: fog_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("camera_bind_group"),
+        })
+    }
+
+    fn begin_pass<'a>(encoder: &'a mut wgpu::CommandEncoder, view: &'a wgpu::TextureView) -> wgpu::RenderPass<'a> {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Environment Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        })
+    }
+
+    // Render a full screen pass into one face and mip level of a cube map for every face and level
+    fn render_cube(&self, encoder: &mut wgpu::CommandEncoder, target: &Texture, mips: u32, pipeline: &RenderPipeline, bind_group: impl Fn(u32, u32) -> BindGroup) {
+        for mip in 0..mips {
+            for face in 0..6 {
+                let view = target.texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("environment_face_view"),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_mip_level: mip,
+                    mip_level_count: std::num::NonZeroU32::new(1),
+                    base_array_layer: face,
+                    array_layer_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                });
+                let bind_group = bind_group(face, mip);
+                let mut pass = Self::begin_pass(encoder, &view);
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.draw(0..3, 0..1);
+            }
+        }
+    }
+
+    fn pass_bind_group(device: &Device, layout: &BindGroupLayout, texture: &Texture, uniform: EnvironmentPassUniform) -> BindGroup {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Pass Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+            label: Some("environment_pass_bind_group"),
+        })
+    }
+
+    // Project an image uploaded with `Texture::from_hdr_image` onto every face of a cube map with a full mip chain
+    pub fn render_equirectangular(&self, device: &Device, encoder: &mut wgpu::CommandEncoder, source: &Texture, source_width: u32, target: &Texture, size: u32) {
+        // A cube face spans a quarter of the image's width, every level reads the image level closest to its own detail
+        self.render_cube(encoder, target, size.ilog2() + 1, &self.equirectangular_pipeline, |face, mip| {
+            let face_size = (size >> mip) as f32;
+            let source_lod = (source_width as f32 / (4.0 * face_size)).log2().clamp(0.0, source_width.ilog2() as f32);
+            Self::pass_bind_group(device, &self.equirectangular_layout, source, EnvironmentPassUniform::new(face, 0.0, source_lod))
+        });
+    }
+
+    // Convert an equirectangular image into the environment cube map and precompute its lighting
+    pub fn load(&mut self, device: &Device, queue: &Queue, img: &image::DynamicImage) -> anyhow::Result<()> {
+        let source = Texture::from_hdr_image(device, queue, img, Some("equirectangular_texture"))?;
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Environment Encoder") });
+
+        self.render_equirectangular(device, &mut encoder, &source, img.width(), &self.cube, CUBE_SIZE);
+
+        // The hemisphere is sampled about every 3 degrees, which matches the detail of a 32 texel wide face
+        let irradiance_lod = (CUBE_SIZE as f32 / 32.0).log2();
+        self.render_cube(&mut encoder, &self.irradiance, 1, &self.irradiance_pipeline, |face, _| {
+            Self::pass_bind_group(device, &self.cube_layout, &self.cube, EnvironmentPassUniform::new(face, 0.0, irradiance_lod))
+        });
+
+        self.render_cube(&mut encoder, &self.prefiltered, PREFILTERED_MIPS, &self.prefilter_pipeline, |face, mip| {
+            let roughness = mip as f32 / (PREFILTERED_MIPS - 1) as f32;
+            let source_lod = (CUBE_SIZE as f32 / (PREFILTERED_SIZE >> mip) as f32).log2();
+            Self::pass_bind_group(device, &self.cube_layout, &self.cube, EnvironmentPassUniform::new(face, roughness, source_lod))
+        });
+
+        queue.submit(std::iter::once(encoder.finish()));
+
+        self.uniform.enabled = 1;
+        self.write(queue);
+        Ok(())
+    }
+
+    // Go back to the flat ambient term of every light
+    pub fn clear(&mut self, queue: &Queue) {
+        self.uniform.enabled = 0;
+        self.write(queue);
+    }
+
+    pub fn set_intensity(&mut self, queue: &Queue, intensity: f32) {
+        self.uniform.intensity = intensity;
+        self.write(queue);
+    }
+
+    fn write(&self, queue: &Queue) {
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct EnvironmentUniform {
+    // Multiplies the light from the environment
+    pub intensity: f32,
+    // Mip level of the prefiltered map holding roughness 1
+    pub max_lod: f32,
+    // 0 while no environment is loaded, the lights' flat ambient term is used instead
+    pub enabled: u32,
+    pub _padding: u32,
+}
+
+impl EnvironmentUniform {
+    pub fn new() -> Self {
+        EnvironmentUniform {
+            intensity: 1.0,
+            max_lod: (PREFILTERED_MIPS - 1) as f32,
+            enabled: 0,
+            _padding: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct EnvironmentPassUniform {
+    face: u32,
+    roughness: f32,
+    source_lod: f32,
+    source_size: f32,
+}
+
+impl EnvironmentPassUniform {
+    fn new(face: u32, roughness: f32, source_lod: f32) -> Self {
+        EnvironmentPassUniform { face, roughness, source_lod, source_size: CUBE_SIZE as f32 }
+    }
+}
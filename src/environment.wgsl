@@ -0,0 +1,209 @@
+// Vertex shader
+
+// Precomputes image based lighting from an equirectangular environment, one cube face and mip level per pass
+
+struct EnvironmentPass {
+    face: u32,
+    roughness: f32,
+    // Mip level of the source to read, chosen so its texels roughly match the target's
+    source_lod: f32,
+    // Width of the source cube map's top level, for picking mip levels while prefiltering
+    source_size: f32,
+}
+@group(0) @binding(0)
+var<uniform> environment_pass: EnvironmentPass;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    // 0,0 in the top left corner of the target, like texture coordinates
+    @location(0) uv: vec2<f32>,
+}
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let position = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u)) * 2.0 - 1.0;
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(position, 0.0, 1.0);
+    out.uv = position * vec2<f32>(0.5, -0.5) + 0.5;
+    return out;
+}
+
+// Fragment shader
+
+let PI: f32 = 3.14159265;
+
+// Direction through a texel of a cube face, following the usual +X, -X, +Y, -Y, +Z, -Z face layout
+fn face_direction(face: u32, uv: vec2<f32>) -> vec3<f32> {
+    let st = uv * 2.0 - 1.0;
+    var direction: vec3<f32>;
+    switch (face) {
+        case 0u: { direction = vec3<f32>(1.0, -st.y, -st.x); }
+        case 1u: { direction = vec3<f32>(-1.0, -st.y, st.x); }
+        case 2u: { direction = vec3<f32>(st.x, 1.0, st.y); }
+        case 3u: { direction = vec3<f32>(st.x, -1.0, -st.y); }
+        case 4u: { direction = vec3<f32>(st.x, -st.y, 1.0); }
+        default: { direction = vec3<f32>(-st.x, -st.y, -1.0); }
+    }
+    return normalize(direction);
+}
+
+@group(0) @binding(1)
+var t_equirectangular: texture_2d<f32>;
+
+// 32 bit float textures can't be filtered everywhere, so the four nearest texels are blended by hand.
+// Columns wrap around at the seam, rows stop at the poles
+fn sample_equirectangular(uv: vec2<f32>, lod: i32) -> vec3<f32> {
+    let size = textureDimensions(t_equirectangular, lod);
+    let position = uv * vec2<f32>(size) - 0.5;
+    let base = vec2<i32>(floor(position));
+    let t = position - floor(position);
+    let x0 = (base.x % size.x + size.x) % size.x;
+    let x1 = (x0 + 1) % size.x;
+    let y0 = clamp(base.y, 0, size.y - 1);
+    let y1 = clamp(base.y + 1, 0, size.y - 1);
+    let top = mix(textureLoad(t_equirectangular, vec2<i32>(x0, y0), lod).rgb, textureLoad(t_equirectangular, vec2<i32>(x1, y0), lod).rgb, t.x);
+    let bottom = mix(textureLoad(t_equirectangular, vec2<i32>(x0, y1), lod).rgb, textureLoad(t_equirectangular, vec2<i32>(x1, y1), lod).rgb, t.x);
+    return mix(top, bottom, t.y);
+}
+
+// Longitude across and latitude down the image, the top row looks straight up
+@fragment
+fn fs_equirectangular(in: VertexOutput) -> @location(0) vec4<f32> {
+    let direction = face_direction(environment_pass.face, in.uv);
+    let uv = vec2<f32>(atan2(direction.z, direction.x) / (2.0 * PI) + 0.5, acos(clamp(direction.y, -1.0, 1.0)) / PI);
+    return vec4<f32>(sample_equirectangular(uv, i32(round(environment_pass.source_lod))), 1.0);
+}
+
+@group(0) @binding(1)
+var t_environment: texture_cube<f32>;
+@group(0) @binding(2)
+var s_environment: sampler;
+
+// Light arriving at a surface facing the direction, integrated over its hemisphere with a cosine weight
+@fragment
+fn fs_irradiance(in: VertexOutput) -> @location(0) vec4<f32> {
+    let normal = face_direction(environment_pass.face, in.uv);
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(normal.y) > 0.999) {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let right = normalize(cross(up, normal));
+    up = cross(normal, right);
+
+    let steps = 64;
+    var irradiance = vec3<f32>(0.0);
+    for (var i = 0; i < steps; i = i + 1) {
+        let phi = (f32(i) + 0.5) / f32(steps) * 2.0 * PI;
+        for (var j = 0; j < steps / 4; j = j + 1) {
+            let theta = (f32(j) + 0.5) / f32(steps / 4) * 0.5 * PI;
+            let tangent = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let direction = tangent.x * right + tangent.y * up + tangent.z * normal;
+            let radiance = textureSampleLevel(t_environment, s_environment, direction, environment_pass.source_lod).rgb;
+            // Larger rings near the horizon cover more solid angle
+            irradiance = irradiance + radiance * cos(theta) * sin(theta);
+        }
+    }
+    return vec4<f32>(PI * irradiance / f32(steps * steps / 4), 1.0);
+}
+
+// Low discrepancy sequence spreading the samples evenly, written without bit reversal builtins for WebGL
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    var bits = i;
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return vec2<f32>(f32(i) / f32(count), f32(bits) * 2.3283064365386963e-10);
+}
+
+// Half vector around the normal, distributed like the GGX microfacets of a surface with the given roughness
+fn importance_sample_ggx(xi: vec2<f32>, normal: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    let h = vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+
+    var up = vec3<f32>(0.0, 0.0, 1.0);
+    if (abs(normal.z) > 0.999) {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    return normalize(tangent * h.x + bitangent * h.y + normal * h.z);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// Reflections of increasingly rough surfaces, one roughness per mip level. The view direction is
+// assumed to be the normal, which trades stretched highlights at grazing angles for a single lookup
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    let normal = face_direction(environment_pass.face, in.uv);
+    let roughness = environment_pass.roughness;
+    // Solid angle of one texel of the source
+    let texel_angle = 4.0 * PI / (6.0 * environment_pass.source_size * environment_pass.source_size);
+
+    let count = 64u;
+    var color = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < count; i = i + 1u) {
+        let h = importance_sample_ggx(hammersley(i, count), normal, roughness);
+        let l = normalize(2.0 * dot(normal, h) * h - normal);
+        let n_dot_l = dot(normal, l);
+        if (n_dot_l > 0.0) {
+            // Read unlikely directions from a blurrier level, a few samples would otherwise pick out single bright texels
+            let n_dot_h = max(dot(normal, h), 0.0);
+            let pdf = distribution_ggx(n_dot_h, roughness) / 4.0 + 0.0001;
+            let sample_angle = 1.0 / (f32(count) * pdf + 0.0001);
+            var lod = environment_pass.source_lod;
+            if (roughness > 0.0) {
+                lod = max(lod, 0.5 * log2(sample_angle / texel_angle) + 1.0);
+            }
+            color = color + textureSampleLevel(t_environment, s_environment, l, lod).rgb * n_dot_l;
+            weight = weight + n_dot_l;
+        }
+    }
+    return vec4<f32>(color / weight, 1.0);
+}
+
+fn geometry_schlick_ggx(n_dot_x: f32, roughness: f32) -> f32 {
+    // Image based lighting remaps the roughness differently than analytic lights
+    let k = roughness * roughness / 2.0;
+    return n_dot_x / (n_dot_x * (1.0 - k) + k);
+}
+
+// Scale (red) and bias (green) applied to F0 by the specular BRDF, indexed by the angle to the viewer and roughness
+@fragment
+fn fs_brdf(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n_dot_v = max(in.uv.x, 0.001);
+    let roughness = in.uv.y;
+    let v = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    let normal = vec3<f32>(0.0, 0.0, 1.0);
+
+    let count = 256u;
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < count; i = i + 1u) {
+        let h = importance_sample_ggx(hammersley(i, count), normal, roughness);
+        let l = normalize(2.0 * dot(v, h) * h - v);
+        let n_dot_l = max(l.z, 0.0);
+        if (n_dot_l > 0.0) {
+            let n_dot_h = max(h.z, 0.0);
+            let v_dot_h = max(dot(v, h), 0.0);
+            let g = geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+            let g_vis = g * v_dot_h / (n_dot_h * n_dot_v);
+            let fc = pow(1.0 - v_dot_h, 5.0);
+            scale = scale + (1.0 - fc) * g_vis;
+            bias = bias + fc * g_vis;
+        }
+    }
+    return vec4<f32>(scale / f32(count), bias / f32(count), 0.0, 1.0);
+}
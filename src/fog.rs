@@ -0,0 +1,111 @@
+use wgpu::{Buffer, Device, Queue};
+use wgpu::util::DeviceExt;
+
+use crate::color::Color;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FogMode {
+    None,
+    // Fades in evenly from `start` to `end`
+    Linear,
+    // Thickens with distance by `density`, never quite hiding anything
+    Exponential,
+    // Like exponential, but stays clear for longer close to the camera
+    ExponentialSquared,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FogSettings {
+    pub mode: FogMode,
+    // Distances from the camera where linear fog starts and where it hides everything
+    pub start: f32,
+    pub end: f32,
+    // How quickly exponential fog thickens with distance
+    pub density: f32,
+    // Density of the height fog at `height`, 0 turns it off. It comes on top of the distance fog
+    pub height_density: f32,
+    pub height: f32,
+    // How quickly the height fog thins out above `height` and thickens below it
+    pub height_falloff: f32,
+    // The clear color when unset, so distant objects fade into the background. That's still the
+    // clear color with a gradient, cube map or environment sky, which the fog doesn't sample
+    pub color: Option<Color>,
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        FogSettings {
+            mode: FogMode::None,
+            start: 10.0,
+            end: 50.0,
+            density: 0.05,
+            height_density: 0.0,
+            height: 0.0,
+            height_falloff: 1.0,
+            color: None,
+        }
+    }
+}
+
+pub struct Context {
+    pub settings: FogSettings,
+    uniform: FogUniform,
+    pub buffer: Buffer,
+}
+
+impl Context {
+    pub fn new(device: &Device, settings: FogSettings) -> Self {
+        let uniform = FogUniform::default();
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Fog Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        Context {
+            settings,
+            uniform,
+            buffer,
+        }
+    }
+
+    pub fn set_settings(&mut self, mut settings: FogSettings) {
+        settings.start = settings.start.max(0.0);
+        settings.end = settings.end.max(settings.start + 0.001);
+        settings.density = settings.density.max(0.0);
+        settings.height_density = settings.height_density.max(0.0);
+        settings.height_falloff = settings.height_falloff.max(0.001);
+        self.settings = settings;
+    }
+
+    // Upload the settings, resolving the color against the current clear color
+    pub fn update(&mut self, queue: &Queue, clear_color: Color) {
+        let settings = self.settings;
+        self.uniform = FogUniform {
+            color: settings.color.unwrap_or(clear_color).color(),
+            mode: settings.mode as u32,
+            start: settings.start,
+            end: settings.end,
+            density: settings.density,
+            height_density: settings.height_density,
+            height: settings.height,
+            height_falloff: settings.height_falloff,
+            _padding: [0; 2],
+        };
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct FogUniform {
+    pub color: [f32; 3],
+    pub mode: u32,
+    pub start: f32,
+    pub end: f32,
+    pub density: f32,
+    pub height_density: f32,
+    pub height: f32,
+    pub height_falloff: f32,
+    pub _padding: [u32; 2],
+}
@@ -0,0 +1,72 @@
+// Vertex shader
+
+// Fast approximate anti-aliasing: finds edges by their contrast in the tonemapped image and blurs
+// along them, without needing any extra samples while rendering
+
+@group(0) @binding(0)
+var t_color: texture_2d<f32>;
+@group(0) @binding(1)
+var s_color: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let position = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u)) * 2.0 - 1.0;
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(position, 0.0, 1.0);
+    out.uv = position * vec2<f32>(0.5, -0.5) + 0.5;
+    return out;
+}
+
+// Fragment shader
+
+// Longest blur along an edge in pixels
+let SPAN_MAX: f32 = 8.0;
+// Keep flat and dark areas from being blurred by tiny gradients
+let REDUCE_MUL: f32 = 0.125;
+let REDUCE_MIN: f32 = 0.0078125;
+
+// Brightness roughly as perceived, the texture holds linear values
+fn luma(color: vec3<f32>) -> f32 {
+    return sqrt(dot(color, vec3<f32>(0.299, 0.587, 0.114)));
+}
+
+fn sample_color(uv: vec2<f32>) -> vec3<f32> {
+    return textureSampleLevel(t_color, s_color, uv, 0.0).rgb;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(t_color));
+    let center = textureSampleLevel(t_color, s_color, in.uv, 0.0);
+    let luma_nw = luma(sample_color(in.uv + vec2<f32>(-1.0, -1.0) * texel));
+    let luma_ne = luma(sample_color(in.uv + vec2<f32>(1.0, -1.0) * texel));
+    let luma_sw = luma(sample_color(in.uv + vec2<f32>(-1.0, 1.0) * texel));
+    let luma_se = luma(sample_color(in.uv + vec2<f32>(1.0, 1.0) * texel));
+    let luma_m = luma(center.rgb);
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    // Direction along the edge, perpendicular to the gradient across the corners
+    var direction = vec2<f32>(
+        (luma_sw + luma_se) - (luma_nw + luma_ne),
+        (luma_nw + luma_sw) - (luma_ne + luma_se),
+    );
+    let reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * REDUCE_MUL, REDUCE_MIN);
+    let scale = 1.0 / (min(abs(direction.x), abs(direction.y)) + reduce);
+    direction = clamp(direction * scale, vec2<f32>(-SPAN_MAX), vec2<f32>(SPAN_MAX)) * texel;
+
+    let near = 0.5 * (sample_color(in.uv + direction * (1.0 / 3.0 - 0.5)) + sample_color(in.uv + direction * (2.0 / 3.0 - 0.5)));
+    let far = near * 0.5 + 0.25 * (sample_color(in.uv - direction * 0.5) + sample_color(in.uv + direction * 0.5));
+    // The wider blur crossed into something else when it leaves the local brightness range
+    let luma_far = luma(far);
+    if (luma_far < luma_min || luma_far > luma_max) {
+        return vec4<f32>(near, center.a);
+    }
+    return vec4<f32>(far, center.a);
+}
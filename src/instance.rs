@@ -1,21 +1,60 @@
-
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
+use cgmath::{Matrix, One, SquareMatrix};
+
 use crate::model;
+use crate::model::Area3D;
+use crate::model::ModelId;
+
+// Handle to one instance of a loaded model
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InstanceId {
+    pub model: ModelId,
+    pub(crate) id: usize,
+}
 
-pub(crate) struct Instance {
+#[derive(Clone, Copy, Debug)]
+pub struct Instance {
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
+    pub scale: cgmath::Vector3<f32>,
 }
 
 impl Instance {
-    pub fn to_raw(&self) -> InstanceRaw {
-        let model =
-            cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation);
+    pub fn new(position: Area3D) -> Self {
+        let Area3D(x, y, z) = position;
+        Instance {
+            position: cgmath::Vector3 { x, y, z },
+            rotation: cgmath::Quaternion::one(),
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn with_scale(mut self, scale: Area3D) -> Self {
+        let Area3D(x, y, z) = scale;
+        self.scale = cgmath::Vector3 { x, y, z };
+        self
+    }
+
+    pub fn with_uniform_scale(mut self, scale: f32) -> Self {
+        self.scale = cgmath::Vector3::new(scale, scale, scale);
+        self
+    }
+
+    pub(crate) fn to_raw(self) -> InstanceRaw {
+        let model = cgmath::Matrix4::from_translation(self.position)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z);
+
+        // Normals need the inverse-transpose so they stay perpendicular to stretched surfaces,
+        // a degenerate (zero) scale falls back to the plain rotation
+        let linear = cgmath::Matrix3::from_cols(model.x.truncate(), model.y.truncate(), model.z.truncate());
+        let normal = linear.invert().map(|m| m.transpose()).unwrap_or_else(|| cgmath::Matrix3::from(self.rotation));
+
         InstanceRaw {
             model: model.into(),
-            normal: cgmath::Matrix3::from(self.rotation).into(),
+            normal: normal.into(),
         }
     }
 }
@@ -73,3 +112,34 @@ impl model::Vertex for InstanceRaw {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{InnerSpace, Rotation3};
+
+    #[test]
+    fn normals_stay_perpendicular_under_non_uniform_scale() {
+        let instance = Instance {
+            rotation: cgmath::Quaternion::from_angle_y(cgmath::Deg(30.0)),
+            ..Instance::new(Area3D(1.0, 2.0, 3.0)).with_scale(Area3D(4.0, 1.0, 0.5))
+        };
+        let raw = instance.to_raw();
+        let model = cgmath::Matrix4::from(raw.model);
+        let normal = cgmath::Matrix3::from(raw.normal);
+
+        // A slanted surface, with its normal and two directions along it
+        let surface_normal = cgmath::Vector3::new(1.0, 1.0, 1.0).normalize();
+        for along in [cgmath::Vector3::new(1.0, -1.0, 0.0), cgmath::Vector3::new(0.0, 1.0, -1.0)] {
+            let along = (model * along.extend(0.0)).truncate();
+            assert!((normal * surface_normal).dot(along).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn zero_scale_keeps_the_rotation() {
+        let rotation = cgmath::Quaternion::from_angle_x(cgmath::Deg(90.0));
+        let raw = Instance { rotation, ..Instance::new(Area3D(0.0, 0.0, 0.0)).with_uniform_scale(0.0) }.to_raw();
+        assert_eq!(cgmath::Matrix3::from(raw.normal), cgmath::Matrix3::from(rotation));
+    }
+}
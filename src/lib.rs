@@ -7,7 +7,41 @@ mod light;
 mod color;
 mod instance;
 mod context;
+mod shadow;
+mod environment;
+mod ssao;
+mod fog;
+mod sky;
+mod tonemap;
+mod bloom;
+mod antialiasing;
+mod post;
+mod mipmap;
 
 use crate::context::Context as CanvasContext;
 use crate::camera::Context as CameraContext;
-use crate::light::Context as LightContext;
\ No newline at end of file
+use crate::light::Context as LightContext;
+use crate::shadow::Context as ShadowContext;
+use crate::environment::Context as EnvironmentContext;
+use crate::ssao::Context as SsaoContext;
+use crate::fog::Context as FogContext;
+use crate::sky::Context as SkyContext;
+use crate::tonemap::Context as TonemapContext;
+use crate::bloom::Context as BloomContext;
+use crate::antialiasing::Context as AntiAliasingContext;
+use crate::post::Context as PostContext;
+
+pub use crate::world::World;
+pub use crate::model::{Area3D, ModelId};
+pub use crate::instance::{Instance, InstanceId};
+pub use crate::color::Color;
+pub use crate::texture::{AddressMode, FilterMode, SamplerOptions, TextureOptions};
+pub use crate::shadow::ShadowSettings;
+pub use crate::ssao::SsaoSettings;
+pub use crate::fog::{FogMode, FogSettings};
+pub use crate::sky::Sky;
+pub use crate::tonemap::{Tonemapper, TonemapSettings};
+pub use crate::bloom::BloomSettings;
+pub use crate::antialiasing::AntiAliasing;
+pub use crate::post::{PostProcess, PostProcessId, LutId};
+pub use crate::light::{Light, LightId, PointLight, DirectionalLight, SpotLight};
@@ -1,41 +1,401 @@
-use wgpu::{RenderPipeline, BindGroup, Buffer};
+use wgpu::{RenderPipeline, BindGroup, BindGroupLayout, Buffer, Device, Queue};
 use crate::color::Color;
 use crate::model::Area3D;
 
+// Handle to a light added to the world
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LightId(usize);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Light {
+    Point(PointLight),
+    // A sun infinitely far away, lighting everything from one direction
+    Directional(DirectionalLight),
+    // A cone of light, like a flashlight
+    Spot(SpotLight),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PointLight {
+    pub position: Area3D,
+    pub color: Color,
+    // Distance at which the light has faded out completely
+    pub range: f32,
+    // Render a cube shadow map for this light when it fits in `ShadowSettings::max_point_shadows`,
+    // the main light always casts shadows
+    pub cast_shadows: bool,
+}
+
+impl PointLight {
+    pub fn new(position: Area3D, color: Color) -> Self {
+        PointLight { position, color, range: 20.0, cast_shadows: false }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DirectionalLight {
+    // Direction the light travels in
+    pub direction: Area3D,
+    pub color: Color,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Area3D, color: Color) -> Self {
+        DirectionalLight { direction, color }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SpotLight {
+    pub position: Area3D,
+    // Direction the cone points in
+    pub direction: Area3D,
+    pub color: Color,
+    pub range: f32,
+    // Half angles of the cone in degrees, full brightness inside `inner_angle` fading to nothing at `outer_angle`
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+}
+
+impl SpotLight {
+    pub fn new(position: Area3D, direction: Area3D, color: Color) -> Self {
+        SpotLight { position, direction, color, range: 20.0, inner_angle: 20.0, outer_angle: 30.0 }
+    }
+}
+
+impl From<PointLight> for Light {
+    fn from(light: PointLight) -> Self {
+        Light::Point(light)
+    }
+}
+
+impl From<DirectionalLight> for Light {
+    fn from(light: DirectionalLight) -> Self {
+        Light::Directional(light)
+    }
+}
+
+impl From<SpotLight> for Light {
+    fn from(light: SpotLight) -> Self {
+        Light::Spot(light)
+    }
+}
+
+impl Light {
+    pub fn color(&self) -> Color {
+        match self {
+            Light::Point(l) => l.color,
+            Light::Directional(l) => l.color,
+            Light::Spot(l) => l.color,
+        }
+    }
+
+    // Directional lights have no position
+    pub fn position(&self) -> Option<Area3D> {
+        match self {
+            Light::Point(l) => Some(l.position),
+            Light::Directional(_) => None,
+            Light::Spot(l) => Some(l.position),
+        }
+    }
+
+    pub(crate) fn to_raw(self) -> LightUniform {
+        match self {
+            Light::Point(l) => LightUniform {
+                position: l.position.position(),
+                range: l.range,
+                color: l.color.color(),
+                kind: LightUniform::POINT,
+                ..LightUniform::default()
+            },
+            Light::Directional(l) => LightUniform {
+                color: l.color.color(),
+                kind: LightUniform::DIRECTIONAL,
+                direction: l.direction.position(),
+                ..LightUniform::default()
+            },
+            Light::Spot(l) => {
+                // The shader's smoothstep needs the inner edge strictly inside the outer one, an inner
+                // angle at or past the outer one gives the cone a hard edge
+                let outer_cos = l.outer_angle.to_radians().cos();
+                let inner_cos = l.inner_angle.min(l.outer_angle).to_radians().cos().max(outer_cos + 1e-4);
+                LightUniform {
+                    position: l.position.position(),
+                    range: l.range,
+                    color: l.color.color(),
+                    kind: LightUniform::SPOT,
+                    direction: l.direction.position(),
+                    inner_cos,
+                    outer_cos,
+                    ..LightUniform::default()
+                }
+            }
+        }
+    }
+}
+
+// Lights that fit in the uniform buffer used where storage buffers aren't available, such as WebGL
+pub const MAX_UNIFORM_LIGHTS: usize = 64;
+
+// Storage buffers hold any number of lights, devices without them get a fixed size uniform array
+pub fn storage_supported(device: &Device) -> bool {
+    device.limits().max_storage_buffers_per_shader_stage > 0
+}
+
+pub fn binding_type(device: &Device) -> wgpu::BufferBindingType {
+    if storage_supported(device) {
+        wgpu::BufferBindingType::Storage { read_only: true }
+    } else {
+        wgpu::BufferBindingType::Uniform
+    }
+}
+
+// Placeholders the light list of `shader.wgsl` and `light.wgsl` is declared with, filled in by `shader_source`
+const ADDRESS_SPACE_TOKEN: &str = "LIGHTS_ADDRESS_SPACE";
+const ARRAY_TOKEN: &str = "LIGHTS_ARRAY";
+
+// Declare the light list of `shader.wgsl` or `light.wgsl` as a storage buffer, or as a uniform
+// buffer of `MAX_UNIFORM_LIGHTS` where `storage_supported` is false
+pub fn shader_source(source: &str, storage: bool) -> String {
+    for token in [ADDRESS_SPACE_TOKEN, ARRAY_TOKEN] {
+        assert!(source.contains(token), "shader doesn't declare its light list with {}", token);
+    }
+    let (address_space, array) = if storage {
+        ("storage, read".to_string(), "array<Light>".to_string())
+    } else {
+        ("uniform".to_string(), format!("array<Light, {}>", MAX_UNIFORM_LIGHTS))
+    };
+    source.replace(ADDRESS_SPACE_TOKEN, &address_space).replace(ARRAY_TOKEN, &array)
+}
+
 pub struct Context {
-    pub uniform: LightUniform,
+    lights: Vec<(usize, Light)>,
+    next_light: usize,
+    changed: bool,
+    // Lights the buffer has room for, it only grows when it is a storage buffer
+    capacity: usize,
+    storage: bool,
+    // Light buffer index of the light in each point shadow slot
+    shadow_slots: Vec<usize>,
     pub buffer: Buffer,
+    pub layout: BindGroupLayout,
     pub bind_group: BindGroup,
     pub render_pipeline: RenderPipeline,
 }
 
 impl Context {
-    pub fn new(lu: LightUniform, lb: Buffer, lbg: BindGroup, lrp: RenderPipeline) -> Self {
+    pub fn new(device: &Device, layout: BindGroupLayout, lrp: RenderPipeline) -> Self {
+        let storage = storage_supported(device);
+        let capacity = if storage { 16 } else { MAX_UNIFORM_LIGHTS };
+        let (buffer, bind_group) = Self::create_buffer(device, &layout, capacity, storage);
         Context {
-            uniform: lu,
-            buffer: lb,
-            bind_group: lbg,
+            lights: Vec::new(),
+            next_light: 0,
+            changed: true,
+            capacity,
+            storage,
+            shadow_slots: Vec::new(),
+            buffer,
+            layout,
+            bind_group,
             render_pipeline: lrp,
         }
     }
+
+    fn create_buffer(device: &Device, layout: &BindGroupLayout, capacity: usize, storage: bool) -> (Buffer, BindGroup) {
+        let usage = if storage { wgpu::BufferUsages::STORAGE } else { wgpu::BufferUsages::UNIFORM };
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Light Buffer"),
+            size: (std::mem::size_of::<LightsHeader>() + capacity * std::mem::size_of::<LightUniform>()) as wgpu::BufferAddress,
+            usage: usage | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+            label: Some("light_bind_group"),
+        });
+        (buffer, bind_group)
+    }
+
+    pub fn add(&mut self, light: impl Into<Light>) -> LightId {
+        let id = self.next_light;
+        self.next_light += 1;
+        self.lights.push((id, light.into()));
+        self.changed = true;
+        LightId(id)
+    }
+
+    pub fn get(&self, id: LightId) -> Option<&Light> {
+        self.lights.iter().find(|(i, _)| *i == id.0).map(|(_, light)| light)
+    }
+
+    // Replace a light, returns false if it no longer exists
+    pub fn update(&mut self, id: LightId, light: impl Into<Light>) -> bool {
+        match self.lights.iter_mut().find(|(i, _)| *i == id.0) {
+            Some((_, l)) => {
+                *l = light.into();
+                self.changed = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, id: LightId) -> Option<Light> {
+        let index = self.lights.iter().position(|(i, _)| *i == id.0)?;
+        self.changed = true;
+        Some(self.lights.remove(index).1)
+    }
+
+    // Position of the light in the light buffer
+    pub fn index_of(&self, id: LightId) -> Option<usize> {
+        self.lights.iter().position(|(i, _)| *i == id.0)
+    }
+
+    // Lights the shaders see, a uniform buffer leaves out the ones past `MAX_UNIFORM_LIGHTS`
+    pub fn len(&self) -> usize {
+        self.lights.len().min(self.capacity)
+    }
+
+    // Lights in light buffer order
+    pub fn iter(&self) -> impl Iterator<Item = (LightId, &Light)> {
+        self.lights.iter().map(|(id, light)| (LightId(*id), light))
+    }
+
+    // Assign point shadow slots, `slots[n]` being the light buffer index of the light rendered into slot n
+    pub fn set_shadow_slots(&mut self, slots: Vec<usize>) {
+        if slots != self.shadow_slots {
+            self.shadow_slots = slots;
+            self.changed = true;
+        }
+    }
+
+    // Upload the light list, growing the storage buffer when it no longer fits. `main_light` alone
+    // provides the ambient light
+    pub fn write(&mut self, device: &Device, queue: &Queue, main_light: LightId) {
+        if !self.changed {
+            return;
+        }
+        self.changed = false;
+
+        if self.lights.len() > self.capacity {
+            if self.storage {
+                self.capacity = self.lights.len().next_power_of_two();
+                (self.buffer, self.bind_group) = Self::create_buffer(device, &self.layout, self.capacity, self.storage);
+            } else {
+                log::warn!("Only the first {} of {} lights are drawn without storage buffers", self.capacity, self.lights.len());
+            }
+        }
+
+        let main_light = self.index_of(main_light).filter(|index| *index < self.len());
+        let header = LightsHeader {
+            count: self.len() as u32,
+            main_light: main_light.map_or(-1, |index| index as i32),
+            _padding: [0; 2],
+        };
+        let mut lights = self.lights.iter().take(self.capacity).map(|(_, l)| l.to_raw()).collect::<Vec<_>>();
+        for (slot, index) in self.shadow_slots.iter().enumerate() {
+            if let Some(light) = lights.get_mut(*index) {
+                light.shadow_slot = slot as i32;
+            }
+        }
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[header]));
+        if !lights.is_empty() {
+            queue.write_buffer(&self.buffer, std::mem::size_of::<LightsHeader>() as wgpu::BufferAddress, bytemuck::cast_slice(&lights));
+        }
+    }
+}
+
+// Precedes the light array in the light buffer, padded to the 16 byte array alignment
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct LightsHeader {
+    pub count: u32,
+    // Index of the light the ambient term comes from, -1 without one
+    pub main_light: i32,
+    pub _padding: [u32; 2],
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub(crate) struct LightUniform {
     pub position: [f32; 3],
-    pub _padding: u32,
+    pub range: f32,
     pub color: [f32; 3],
-    pub _padding2: u32,
+    pub kind: u32,
+    pub direction: [f32; 3],
+    pub inner_cos: f32,
+    pub outer_cos: f32,
+    // Point shadow slot of the light, -1 when it has no cube shadow map this frame
+    pub shadow_slot: i32,
+    pub _padding: [u32; 2],
 }
 
-impl LightUniform {
-    pub fn new(position: Area3D, color: Color) -> Self {
+impl Default for LightUniform {
+    fn default() -> Self {
         LightUniform {
-            position: position.position(),
-            _padding: 0,
-            color: color.color(),
-            _padding2: 0
+            position: [0.0; 3],
+            range: 0.0,
+            color: [0.0; 3],
+            kind: 0,
+            direction: [0.0; 3],
+            inner_cos: 0.0,
+            outer_cos: 0.0,
+            shadow_slot: -1,
+            _padding: [0; 2],
+        }
+    }
+}
+
+impl LightUniform {
+    // Matches the light kinds in `shader.wgsl` and `light.wgsl`
+    pub const POINT: u32 = 0;
+    pub const DIRECTIONAL: u32 = 1;
+    pub const SPOT: u32 = 2;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shader_source_declares_lights() {
+        for source in [include_str!("shader.wgsl"), include_str!("light.wgsl")] {
+            let storage = shader_source(source, true);
+            assert!(storage.contains("var<storage, read> lights: Lights;"));
+            assert!(storage.contains("lights: array<Light>,"));
+
+            let uniform = shader_source(source, false);
+            assert!(uniform.contains("var<uniform> lights: Lights;"));
+            assert!(uniform.contains(&format!("lights: array<Light, {}>,", MAX_UNIFORM_LIGHTS)));
+            assert!(!uniform.contains(ADDRESS_SPACE_TOKEN) && !uniform.contains(ARRAY_TOKEN));
         }
     }
+
+    #[test]
+    fn spot_inner_angle_clamped() {
+        let spot = |inner_angle, outer_angle| {
+            let light = SpotLight { inner_angle, outer_angle, ..SpotLight::new(Area3D(0.0, 0.0, 0.0), Area3D(0.0, -1.0, 0.0), Color::new(1.0, 1.0, 1.0)) };
+            Light::from(light).to_raw()
+        };
+
+        let raw = spot(20.0, 30.0);
+        assert_eq!(raw.inner_cos, 20.0f32.to_radians().cos());
+        assert_eq!(raw.outer_cos, 30.0f32.to_radians().cos());
+        for (inner, outer) in [(30.0, 30.0), (45.0, 30.0), (0.0, 0.0)] {
+            let raw = spot(inner, outer);
+            assert_eq!(raw.outer_cos, f32::to_radians(outer).cos());
+            assert!(raw.inner_cos > raw.outer_cos, "inner {} outer {}", inner, outer);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "LIGHTS_ADDRESS_SPACE")]
+    fn shader_source_without_placeholders() {
+        shader_source("var<storage, read> lights: Lights;", false);
+    }
 }
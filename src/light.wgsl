@@ -3,16 +3,37 @@
 struct Camera {
     view_pos: vec4<f32>,
     view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+    prev_view_proj: mat4x4<f32>,
+    jitter: vec4<f32>,
 }
 @group(0) @binding(0)
 var<uniform> camera: Camera;
 
+// Light kinds, matching `LightUniform` in light.rs
+let LIGHT_POINT: u32 = 0u;
+let LIGHT_DIRECTIONAL: u32 = 1u;
+let LIGHT_SPOT: u32 = 2u;
+
 struct Light {
     position: vec3<f32>,
+    range: f32,
     color: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    inner_cos: f32,
+    outer_cos: f32,
+    shadow_slot: i32,
+}
+struct Lights {
+    count: u32,
+    // Where the ambient light comes from, -1 without a main light
+    main_light: i32,
+    // `light::shader_source` fills in a runtime sized array, or a fixed one in a uniform buffer
+    lights: LIGHTS_ARRAY,
 }
 @group(1) @binding(0)
-var<uniform> light: Light;
+var<LIGHTS_ADDRESS_SPACE> lights: Lights;
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
@@ -21,22 +42,48 @@ struct VertexInput {
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) color: vec3<f32>,
+    @location(1) world_position: vec3<f32>,
 };
 
 @vertex
 fn vs_main(
     model: VertexInput,
+    // One instance is drawn per light
+    @builtin(instance_index) light_index: u32,
 ) -> VertexOutput {
+    let light = lights.lights[light_index];
     let scale = 0.25;
     var out: VertexOutput;
-    out.clip_position = camera.view_proj * vec4<f32>(model.position * scale + light.position, 1.0);
+    out.world_position = model.position * scale + light.position;
+    out.clip_position = camera.view_proj * vec4<f32>(out.world_position, 1.0);
+    // Directional lights have no position to draw a gizmo at, move them outside the clip volume
+    if (light.kind == LIGHT_DIRECTIONAL) {
+        out.clip_position = vec4<f32>(2.0, 2.0, 2.0, 1.0);
+    }
     out.color = light.color;
     return out;
 }
 
 // Fragment shader
 
+// Screen space motion since the previous frame in texture coordinates for temporal anti-aliasing,
+// followed by the distance along the view direction for post-processing
+fn velocity(world_position: vec3<f32>) -> vec4<f32> {
+    let current = camera.view_proj * vec4<f32>(world_position, 1.0);
+    let previous = camera.prev_view_proj * vec4<f32>(world_position, 1.0);
+    let delta = current.xy / current.w - camera.jitter.xy - previous.xy / previous.w;
+    return vec4<f32>(delta * vec2<f32>(0.5, -0.5), current.w, 0.0);
+}
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    @location(1) velocity: vec4<f32>,
+}
+
 @fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return vec4<f32>(in.color, 1.0);
+fn fs_main(in: VertexOutput) -> FragmentOutput {
+    var out: FragmentOutput;
+    out.color = vec4<f32>(in.color, 1.0);
+    out.velocity = velocity(in.world_position);
+    return out;
 }
\ No newline at end of file
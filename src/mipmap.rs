@@ -0,0 +1,149 @@
+use std::iter;
+use std::num::NonZeroU32;
+
+use wgpu::{BindGroupLayout, Device, Queue, RenderPipeline, Sampler, TextureFormat};
+
+// Formats of the textures images are uploaded into, see `Texture::from_image`
+const FORMATS: [TextureFormat; 2] = [TextureFormat::Rgba8Unorm, TextureFormat::Rgba8UnormSrgb];
+
+// Renders the mip chains of uploaded textures, each level drawn from the one above it. Sampling
+// and rendering through sRGB views averages the colors in linear space
+pub struct Generator {
+    layout: BindGroupLayout,
+    sampler: Sampler,
+    // The target format is baked into a pipeline, so there is one for each of `FORMATS`
+    pipelines: Vec<(TextureFormat, RenderPipeline)>,
+}
+
+impl Generator {
+    pub fn new(device: &Device) -> Self {
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("mipmap_bind_group_layout"),
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Mipmap Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Mipmap Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("mipmap.wgsl").into()),
+        });
+        let pipelines = FORMATS.iter().map(|&format| {
+            let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Mipmap Pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(format.into())],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            });
+            (format, pipeline)
+        }).collect();
+
+        Generator {
+            layout,
+            sampler,
+            pipelines,
+        }
+    }
+
+    // Whether every format images are uploaded into can be rendered to. Textures fall back to
+    // building their mip chains on the CPU where they can't
+    pub fn is_supported(adapter: &wgpu::Adapter) -> bool {
+        FORMATS.iter().all(|&format| {
+            adapter.get_texture_format_features(format).allowed_usages.contains(wgpu::TextureUsages::RENDER_ATTACHMENT)
+        })
+    }
+
+    // Draw every level of `texture` below the first, which must already hold the image. The
+    // texture needs to be created with `RENDER_ATTACHMENT` and `TEXTURE_BINDING` usage
+    pub fn generate(&self, device: &Device, queue: &Queue, texture: &wgpu::Texture, format: TextureFormat, mip_level_count: u32) {
+        let Some((_, pipeline)) = self.pipelines.iter().find(|(f, _)| *f == format) else {
+            log::warn!("Can't generate mipmaps for {:?} textures", format);
+            return;
+        };
+        let level_view = |level| texture.create_view(&wgpu::TextureViewDescriptor {
+            base_mip_level: level,
+            mip_level_count: NonZeroU32::new(1),
+            ..Default::default()
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Mipmap Encoder"),
+        });
+        for level in 1..mip_level_count {
+            let source = level_view(level - 1);
+            let target = level_view(level);
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&source),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&self.sampler),
+                    },
+                ],
+                label: Some("mipmap_bind_group"),
+            });
+
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Mipmap Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        queue.submit(iter::once(encoder.finish()));
+    }
+}
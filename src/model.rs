@@ -4,18 +4,80 @@ use wgpu::util::DeviceExt;
 
 use crate::CameraContext;
 use crate::LightContext;
-use crate::texture::Texture;
+use crate::texture::{AddressMode, Texture, TextureOptions};
+use crate::instance::Instance;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::mem;
+use std::ops::Range;
+
+use cgmath::InnerSpace;
 
 use crate::CanvasContext;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct Area3D(pub f32, pub f32, pub f32);
 
 impl Area3D {
     pub fn position(&self) -> [f32; 3] {[self.0, self.1, self.2]}
 }
 
+// Smooth per-vertex normals for meshes that don't provide any, weighted by triangle area
+pub fn compute_normals(positions: &[[f32; 3]], indices: &[u32]) -> Vec<[f32; 3]> {
+    let mut normals = vec![cgmath::Vector3::new(0.0, 0.0, 0.0); positions.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|i| cgmath::Vector3::from(positions[i as usize]));
+        let normal = (b - a).cross(c - a);
+        triangle.iter().for_each(|i| normals[*i as usize] += normal);
+    }
+    normals.into_iter().map(|n| {
+        if n.magnitude2() > 0.0 { n.normalize().into() } else { [0.0, 1.0, 0.0] }
+    }).collect()
+}
+
+// Per-vertex tangent and bitangent from the triangles' texture coordinates, averaged over the shared vertices.
+// The bitangent points towards the top of the texture, which is +Y in OpenGL style normal maps
+pub fn compute_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    let mut tangents = vec![cgmath::Vector3::new(0.0, 0.0, 0.0); vertices.len()];
+    let mut bitangents = vec![cgmath::Vector3::new(0.0, 0.0, 0.0); vertices.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [v0, v1, v2] = [triangle[0], triangle[1], triangle[2]].map(|i| vertices[i as usize]);
+        let [p0, p1, p2] = [v0, v1, v2].map(|v| cgmath::Vector3::from(v.position));
+        let [uv0, uv1, uv2] = [v0, v1, v2].map(|v| cgmath::Vector2::from(v.tex_coords));
+
+        let (delta_pos1, delta_pos2) = (p1 - p0, p2 - p0);
+        let (delta_uv1, delta_uv2) = (uv1 - uv0, uv2 - uv0);
+        let det = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+        // Degenerate texture coordinates don't define a direction
+        if det.abs() < f32::EPSILON {
+            continue;
+        }
+        let r = 1.0 / det;
+        let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+        // Texture v grows downwards, so flip it to point up the image
+        let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
+
+        for i in triangle {
+            tangents[*i as usize] += tangent;
+            bitangents[*i as usize] += bitangent;
+        }
+    }
+
+    for (i, vertex) in vertices.iter_mut().enumerate() {
+        let normal = cgmath::Vector3::from(vertex.normal);
+        // Keep the tangent perpendicular to the normal, falling back to any perpendicular direction
+        let mut tangent = tangents[i] - normal * normal.dot(tangents[i]);
+        if tangent.magnitude2() < f32::EPSILON {
+            let axis = if normal.x.abs() < 0.9 { cgmath::Vector3::unit_x() } else { cgmath::Vector3::unit_y() };
+            tangent = axis - normal * normal.dot(axis);
+        }
+        let tangent = tangent.normalize();
+        // Mirrored texture coordinates flip the bitangent relative to the normal and tangent
+        let handedness = if normal.cross(tangent).dot(bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+        vertex.tangent = tangent.into();
+        vertex.bitangent = (normal.cross(tangent) * handedness).into();
+    }
+}
+
 pub trait Vertex {
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a>;
 }
@@ -26,6 +88,8 @@ pub struct ModelVertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
     pub normal: [f32; 3],
+    pub tangent: [f32; 3],
+    pub bitangent: [f32; 3],
 }
 
 impl Vertex for ModelVertex {
@@ -49,6 +113,16 @@ impl Vertex for ModelVertex {
                     shader_location: 2,
                     format: VertexFormat::Float32x3,
                 },
+                VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as BufferAddress,
+                    shader_location: 3,
+                    format: VertexFormat::Float32x3,
+                },
+                VertexAttribute {
+                    offset: mem::size_of::<[f32; 11]>() as BufferAddress,
+                    shader_location: 4,
+                    format: VertexFormat::Float32x3,
+                },
             ],
         }
     }
@@ -56,23 +130,200 @@ impl Vertex for ModelVertex {
 
 pub struct Material {
     pub name: String,
-    pub diffuse_texture: Texture,
+    pub textures: MaterialTextures,
+    pub uniform: MaterialUniform,
+    pub buffer: Buffer,
     pub bind_group: BindGroup,
 }
 
+// Missing textures sample the context's white and flat normal textures instead, which leaves the
+// material's constants unchanged
+#[derive(Default)]
+pub struct MaterialTextures {
+    // Base color for PBR materials
+    pub diffuse: Option<Texture>,
+    pub normal: Option<Texture>,
+    // Roughness in the green channel and metalness in the blue one, as in glTF
+    pub metallic_roughness: Option<Texture>,
+    pub occlusion: Option<Texture>,
+    pub emissive: Option<Texture>,
+}
+
 impl Material {
-    pub async fn new(ctx: &mut CanvasContext, file_name: String, m: tobj::Material) -> Self {
-        let diffuse_texture = ctx.load_texture(&m.diffuse_texture).await.unwrap();
-        let bind_group = ctx.create_bind_group(&diffuse_texture);
+    // `keys` are the statements the material sets in its MTL file, see `mtl_keys`. The options of
+    // its texture maps override `options`
+    pub async fn new(ctx: &mut CanvasContext, file_name: String, m: tobj::Material, keys: &HashSet<String>, options: TextureOptions) -> Self {
+        let (diffuse_file, diffuse_options) = parse_texture_map(&m.diffuse_texture, options);
+        let (normal_file, normal_options) = parse_texture_map(&m.normal_texture, options);
+        let textures = MaterialTextures {
+            diffuse: ctx.load_material_texture(&diffuse_file, false, diffuse_options).await,
+            // `map_Bump` holds a tangent space normal map
+            normal: ctx.load_material_texture(&normal_file, true, normal_options).await,
+            ..MaterialTextures::default()
+        };
+        let uniform = MaterialUniform::from_mtl(&m, keys);
+        Self::from_textures(ctx, file_name, textures, uniform)
+    }
+
+    pub fn from_textures(ctx: &mut CanvasContext, name: String, textures: MaterialTextures, uniform: MaterialUniform) -> Self {
+        let buffer = ctx.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some(&format!("{:?} Material Buffer", name)),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+        let bind_group = ctx.create_bind_group(&textures, &buffer);
 
         Material {
-            name: file_name,
-            diffuse_texture,
+            name,
+            textures,
+            uniform,
+            buffer,
             bind_group,
         }
     }
 }
 
+// Statements each material of the MTL files sets, by material name
+pub fn mtl_keys(texts: &[String]) -> HashMap<String, HashSet<String>> {
+    let mut materials = HashMap::new();
+    for text in texts {
+        let mut current: Option<&mut HashSet<String>> = None;
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("newmtl") => current = Some(materials.entry(line.trim()[6..].trim().to_string()).or_default()),
+                Some(key) if !key.starts_with('#') => {
+                    if let Some(keys) = current.as_mut() {
+                        keys.insert(key.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    materials
+}
+
+// Split an MTL texture statement such as `-clamp on -s 2 2 tiles.png` into the file name and the
+// options it sets. tobj keeps the options in front of the name, the ones this renderer has no use
+// for, like the bump multiplier `-bm`, are skipped
+fn parse_texture_map(map: &str, mut options: TextureOptions) -> (String, TextureOptions) {
+    let mut tokens = map.split_whitespace().peekable();
+    while let Some(option) = tokens.next_if(|t| t.starts_with('-')) {
+        match option {
+            "-clamp" => {
+                let mode = if tokens.next() == Some("on") { AddressMode::ClampToEdge } else { AddressMode::Repeat };
+                options.sampler.address_mode_u = mode;
+                options.sampler.address_mode_v = mode;
+            }
+            // Offset, scale and turbulence take one to three numbers
+            "-o" | "-s" | "-t" => {
+                for _ in 0..3 {
+                    if tokens.next_if(|t| t.parse::<f32>().is_ok()).is_none() {
+                        break;
+                    }
+                }
+            }
+            "-mm" => {
+                tokens.nth(1);
+            }
+            "-blendu" | "-blendv" | "-bm" | "-boost" | "-cc" | "-imfchan" | "-texres" | "-type" => {
+                tokens.next();
+            }
+            _ => log::warn!("Unknown texture option {} in {}", option, map),
+        }
+    }
+    (tokens.collect::<Vec<_>>().join(" "), options)
+}
+
+// Constants of a material, the colors multiply the material's textures. Phong materials use the
+// MTL colors, PBR materials the diffuse color as base color along with metalness and roughness
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    // Ka, share of the lights' ambient term the surface reflects
+    pub ambient: [f32; 3],
+    // Ns, the specular exponent
+    pub shininess: f32,
+    // Kd
+    pub diffuse: [f32; 3],
+    // d, 1.0 is opaque
+    pub dissolve: f32,
+    // Ks
+    pub specular: [f32; 3],
+    // SHADING_PHONG or SHADING_PBR
+    pub shading: u32,
+    // Ke, light given off by the surface itself
+    pub emissive: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    // How much the occlusion texture darkens the ambient light, 0.0 ignores it
+    pub occlusion_strength: f32,
+    // ALPHA_BLEND, ALPHA_OPAQUE or ALPHA_MASK
+    pub alpha_mode: u32,
+    // Alpha below which ALPHA_MASK materials are cut out
+    pub alpha_cutoff: f32,
+}
+
+impl MaterialUniform {
+    // Match the shading models in `shader.wgsl`
+    pub const SHADING_PHONG: u32 = 0;
+    pub const SHADING_PBR: u32 = 1;
+    // The alpha modes of glTF. MTL materials blend their texture's alpha and dissolve
+    pub const ALPHA_BLEND: u32 = 0;
+    pub const ALPHA_OPAQUE: u32 = 1;
+    pub const ALPHA_MASK: u32 = 2;
+
+    // MTL files with the PBR extension's `Pr` or `Pm` get PBR shading, everything else stays Phong.
+    // Colors and shininess missing from `keys` keep their defaults rather than tobj's zeros
+    pub fn from_mtl(m: &tobj::Material, keys: &HashSet<String>) -> Self {
+        // tobj leaves the parameters it doesn't know in `unknown_param`
+        let floats = |key: &str| m.unknown_param.get(key).map(|v| v.split_whitespace().filter_map(|v| v.parse::<f32>().ok()).collect::<Vec<_>>());
+        let emissive = floats("Ke").and_then(|ke| match ke[..] {
+            [r, g, b, ..] => Some([r, g, b]),
+            [v] => Some([v; 3]),
+            _ => None,
+        }).unwrap_or([0.0; 3]);
+        let roughness = floats("Pr").and_then(|v| v.first().copied());
+        let metallic = floats("Pm").and_then(|v| v.first().copied());
+
+        let defaults = Self::default();
+        let declared = |key: &str| keys.contains(key);
+        MaterialUniform {
+            ambient: if declared("Ka") { m.ambient } else { defaults.ambient },
+            shininess: if declared("Ns") { m.shininess } else { defaults.shininess },
+            diffuse: if declared("Kd") { m.diffuse } else { defaults.diffuse },
+            dissolve: m.dissolve,
+            specular: if declared("Ks") { m.specular } else { defaults.specular },
+            shading: if roughness.is_some() || metallic.is_some() { Self::SHADING_PBR } else { Self::SHADING_PHONG },
+            emissive,
+            metallic: metallic.unwrap_or(0.0),
+            roughness: roughness.unwrap_or(1.0),
+            ..Self::default()
+        }
+    }
+}
+
+// What the shader used before materials carried their own constants, for formats without Phong parameters
+impl Default for MaterialUniform {
+    fn default() -> Self {
+        MaterialUniform {
+            ambient: [1.0; 3],
+            shininess: 32.0,
+            diffuse: [1.0; 3],
+            dissolve: 1.0,
+            specular: [1.0; 3],
+            shading: Self::SHADING_PHONG,
+            emissive: [0.0; 3],
+            metallic: 0.0,
+            roughness: 1.0,
+            occlusion_strength: 1.0,
+            alpha_mode: Self::ALPHA_BLEND,
+            alpha_cutoff: 0.5,
+        }
+    }
+}
+
 pub struct Mesh {
     pub name: String,
     pub vertex_buffer: Buffer,
@@ -82,101 +333,243 @@ pub struct Mesh {
 }
 
 impl Mesh {
-    pub fn new(ctx: &mut CanvasContext, file_name: String, m: tobj::Model) -> Self {
-        let vertices = (0..m.mesh.positions.len() / 3).map(|i| ModelVertex {
-            position: [
-                m.mesh.positions[i * 3],
-                m.mesh.positions[i * 3 + 1],
-                m.mesh.positions[i * 3 + 2],
-            ],
-            tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-            normal: [
-                m.mesh.normals[i * 3],
-                m.mesh.normals[i * 3 + 1],
-                m.mesh.normals[i * 3 + 2],
-            ],
+    // Meshes without a material, or with one the library didn't define, use `default_material`
+    pub fn new(ctx: &mut CanvasContext, file_name: String, m: tobj::Model, default_material: usize) -> Self {
+        let positions = m.mesh.positions.chunks_exact(3).map(|p| [p[0], p[1], p[2]]).collect::<Vec<_>>();
+        // Untextured exports often leave out texture coordinates and normals
+        let normals = if m.mesh.normals.is_empty() {
+            compute_normals(&positions, &m.mesh.indices)
+        } else {
+            m.mesh.normals.chunks_exact(3).map(|n| [n[0], n[1], n[2]]).collect()
+        };
+        let mut vertices = (0..positions.len()).map(|i| ModelVertex {
+            position: positions[i],
+            tex_coords: m.mesh.texcoords.get(i * 2..i * 2 + 2).map_or([0.0, 0.0], |t| [t[0], t[1]]),
+            normal: normals[i],
+            tangent: [0.0; 3],
+            bitangent: [0.0; 3],
         }).collect::<Vec<_>>();
+        compute_tangents(&mut vertices, &m.mesh.indices);
+
+        let material = m.mesh.material_id.filter(|id| *id < default_material).unwrap_or(default_material);
+        Self::from_vertices(ctx, file_name, &vertices, &m.mesh.indices, material)
+    }
 
+    pub fn from_vertices(ctx: &mut CanvasContext, name: String, vertices: &[ModelVertex], indices: &[u32], material: usize) -> Self {
         let vertex_buffer = ctx.device.create_buffer_init(&BufferInitDescriptor {
-            label: Some(&format!("{:?} Vertex Buffer", file_name)),
-            contents: bytemuck::cast_slice(&vertices),
+            label: Some(&format!("{:?} Vertex Buffer", name)),
+            contents: bytemuck::cast_slice(vertices),
             usage: BufferUsages::VERTEX,
         });
         let index_buffer = ctx.device.create_buffer_init(&BufferInitDescriptor {
-            label: Some(&format!("{:?} Index Buffer", file_name)),
-            contents: bytemuck::cast_slice(&m.mesh.indices),
+            label: Some(&format!("{:?} Index Buffer", name)),
+            contents: bytemuck::cast_slice(indices),
             usage: BufferUsages::INDEX,
         });
 
         Mesh {
-            name: file_name,
+            name,
             vertex_buffer,
             index_buffer,
-            num_elements: m.mesh.indices.len() as u32, 
-            material: m.mesh.material_id.unwrap_or(0)
+            num_elements: indices.len() as u32,
+            material,
         }
     }
 }
 
+// Handle to a model loaded into the world
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ModelId(pub(crate) usize);
+
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
-    pub area: Area3D
+    // Instances keyed by their id. Ids only grow, so iterating them follows spawn order
+    pub instances: BTreeMap<usize, Instance>,
+    // The slice of the shared instance buffer holding this model's instances
+    pub instance_range: Range<u32>,
+    next_instance: usize,
 }
 
 impl Model {
-    pub fn new(ctx: &mut CanvasContext, area: Area3D, models: Vec<tobj::Model>, materials: Vec<Material>, file_name: String) -> Self {
+    // The last of `materials` is the fallback for meshes without one
+    pub fn new(ctx: &mut CanvasContext, models: Vec<tobj::Model>, materials: Vec<Material>, file_name: String) -> Self {
+        let default_material = materials.len() - 1;
         let meshes = models.into_iter().map(|m| {
-            Mesh::new(ctx, file_name.clone(), m)
+            Mesh::new(ctx, file_name.clone(), m, default_material)
         }).collect::<Vec<_>>();
 
-        Self { meshes, materials, area }
+        Self::from_meshes(meshes, materials)
+    }
+
+    pub fn from_meshes(meshes: Vec<Mesh>, materials: Vec<Material>) -> Self {
+        Self { meshes, materials, instances: BTreeMap::new(), instance_range: 0..0, next_instance: 0 }
+    }
+
+    pub fn spawn(&mut self, instance: Instance) -> usize {
+        let id = self.next_instance;
+        self.next_instance += 1;
+        self.instances.insert(id, instance);
+        id
+    }
+
+    pub fn instance(&self, id: usize) -> Option<&Instance> {
+        self.instances.get(&id)
+    }
+
+    pub fn instance_mut(&mut self, id: usize) -> Option<&mut Instance> {
+        self.instances.get_mut(&id)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Instance> {
+        self.instances.remove(&id)
     }
 
     pub fn draw<'a>(&'a self, render_pass: &mut RenderPass<'a>, camera: &'a CameraContext, light: &'a LightContext) {
-        render_pass.draw_model(&self, &camera.bind_group, &light.bind_group);
+        render_pass.draw_model(self, &camera.bind_group, &light.bind_group);
+    }
+
+    // Draw depth only into a shadow map, `pass` holds the matrix of the map being rendered
+    pub fn shadow<'a>(&'a self, render_pass: &mut RenderPass<'a>, pass: &'a BindGroup) {
+        render_pass.draw_shadow_model(self, pass);
     }
 
     pub fn light<'a>(&'a self, render_pass: &mut RenderPass<'a>, camera: &'a CameraContext, light: &'a LightContext) {
-        render_pass.draw_light_model(&self, &camera.bind_group, &light.bind_group);
+        render_pass.draw_light_model(self, 0..light.len() as u32, &camera.bind_group, &light.bind_group);
     }
 }
 
 pub trait DrawModel<'a> {
-    fn draw_mesh(&mut self, mesh: &'a Mesh, material: &'a Material, camera: &'a BindGroup, light: &'a BindGroup);
+    fn draw_mesh(&mut self, mesh: &'a Mesh, material: &'a Material, instances: Range<u32>, camera: &'a BindGroup, light: &'a BindGroup);
     fn draw_model(&mut self, model: &'a Model, camera: &'a BindGroup, light: &'a BindGroup);
 }
 
 impl<'a, 'b> DrawModel<'b> for RenderPass<'a> where 'b: 'a {
-    fn draw_mesh(&mut self, mesh: &'b Mesh, material: &'b Material, camera: &'b BindGroup, light: &'b BindGroup,) {
+    fn draw_mesh(&mut self, mesh: &'b Mesh, material: &'b Material, instances: Range<u32>, camera: &'b BindGroup, light: &'b BindGroup,) {
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
         self.set_index_buffer(mesh.index_buffer.slice(..), IndexFormat::Uint32);
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, camera, &[]);
         self.set_bind_group(2, light, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, 0..1);
+        self.draw_indexed(0..mesh.num_elements, 0, instances);
     }
 
     fn draw_model(&mut self, model: &'b Model, camera: &'b BindGroup, light: &'a BindGroup) {
-        model.meshes.iter().for_each(|mesh| self.draw_mesh(mesh, &model.materials[mesh.material], camera, light));
+        if model.instance_range.is_empty() {
+            return;
+        }
+        model.meshes.iter().for_each(|mesh| self.draw_mesh(mesh, &model.materials[mesh.material], model.instance_range.clone(), camera, light));
     }
 }
 
 pub trait DrawLight<'a> {
-    fn draw_light_mesh(&mut self, mesh: &'a Mesh, camera: &'a BindGroup, light: &'a BindGroup);
-    fn draw_light_model(&mut self, model: &'a Model, camera: &'a BindGroup, light: &'a BindGroup);
+    fn draw_light_mesh(&mut self, mesh: &'a Mesh, lights: Range<u32>, camera: &'a BindGroup, light: &'a BindGroup);
+    fn draw_light_model(&mut self, model: &'a Model, lights: Range<u32>, camera: &'a BindGroup, light: &'a BindGroup);
 }
 
 impl<'a, 'b> DrawLight<'b> for RenderPass<'a> where 'b: 'a {
-    fn draw_light_mesh(&mut self, mesh: &'b Mesh, camera: &'b BindGroup, light: &'b BindGroup) {
+    // Each light in the range is drawn as one instance of the mesh
+    fn draw_light_mesh(&mut self, mesh: &'b Mesh, lights: Range<u32>, camera: &'b BindGroup, light: &'b BindGroup) {
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
         self.set_index_buffer(mesh.index_buffer.slice(..), IndexFormat::Uint32);
         self.set_bind_group(0, camera, &[]);
         self.set_bind_group(1, light, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, 0..1);
+        self.draw_indexed(0..mesh.num_elements, 0, lights);
     }
 
-    fn draw_light_model(&mut self, model: &'b Model, camera: &'b BindGroup, light: &'b BindGroup) {
-        model.meshes.iter().for_each(|mesh| self.draw_light_mesh(mesh, camera, light));
+    fn draw_light_model(&mut self, model: &'b Model, lights: Range<u32>, camera: &'b BindGroup, light: &'b BindGroup) {
+        model.meshes.iter().for_each(|mesh| self.draw_light_mesh(mesh, lights.clone(), camera, light));
+    }
+}
+
+pub trait DrawShadow<'a> {
+    fn draw_shadow_mesh(&mut self, mesh: &'a Mesh, instances: Range<u32>, shadow: &'a BindGroup);
+    fn draw_shadow_model(&mut self, model: &'a Model, shadow: &'a BindGroup);
+}
+
+impl<'a, 'b> DrawShadow<'b> for RenderPass<'a> where 'b: 'a {
+    fn draw_shadow_mesh(&mut self, mesh: &'b Mesh, instances: Range<u32>, shadow: &'b BindGroup) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_index_buffer(mesh.index_buffer.slice(..), IndexFormat::Uint32);
+        self.set_bind_group(0, shadow, &[]);
+        self.draw_indexed(0..mesh.num_elements, 0, instances);
+    }
+
+    fn draw_shadow_model(&mut self, model: &'b Model, shadow: &'b BindGroup) {
+        if model.instance_range.is_empty() {
+            return;
+        }
+        model.meshes.iter().for_each(|mesh| self.draw_shadow_mesh(mesh, model.instance_range.clone(), shadow));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(position: [f32; 3], tex_coords: [f32; 2]) -> ModelVertex {
+        ModelVertex { position, tex_coords, normal: [0.0, 0.0, 1.0], tangent: [0.0; 3], bitangent: [0.0; 3] }
+    }
+
+    #[test]
+    fn texture_map_options() {
+        let (name, options) = parse_texture_map("-clamp on -s 2 2 -bm 0.5 tiles.png", TextureOptions::default());
+        assert_eq!(name, "tiles.png");
+        assert_eq!(options.sampler.address_mode_u, AddressMode::ClampToEdge);
+        assert_eq!(options.sampler.address_mode_v, AddressMode::ClampToEdge);
+
+        // Numbers after an option are only consumed up to the option's arity, file names keep their spaces
+        let (name, options) = parse_texture_map("-o 1 -clamp off -mm 0 1 my texture.png", options);
+        assert_eq!(name, "my texture.png");
+        assert_eq!(options.sampler.address_mode_u, AddressMode::Repeat);
+
+        let (name, options) = parse_texture_map("plain.png", TextureOptions::default());
+        assert_eq!(name, "plain.png");
+        assert_eq!(options.sampler, TextureOptions::default().sampler);
+    }
+
+    #[test]
+    fn mtl_keys_by_material() {
+        let texts = [
+            "# comment\nnewmtl first\nKd 1 0 0\nmap_Kd a.png\n\nnewmtl second one\nd 0.5\n".to_string(),
+            "newmtl first\n  Ns 10\n# Ks 1 1 1\n".to_string(),
+        ];
+        let keys = mtl_keys(&texts);
+        let set = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<HashSet<_>>();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys["first"], set(&["Kd", "map_Kd", "Ns"]));
+        assert_eq!(keys["second one"], set(&["d"]));
+    }
+
+    #[test]
+    fn tangents_follow_texture_coordinates() {
+        // A quad in the XY plane, texture u along +X and v growing downwards along -Y
+        let mut vertices = [
+            vertex([0.0, 0.0, 0.0], [0.0, 1.0]),
+            vertex([1.0, 0.0, 0.0], [1.0, 1.0]),
+            vertex([1.0, 1.0, 0.0], [1.0, 0.0]),
+            vertex([0.0, 1.0, 0.0], [0.0, 0.0]),
+        ];
+        compute_tangents(&mut vertices, &[0, 1, 2, 0, 2, 3]);
+        for v in &vertices {
+            assert!((cgmath::Vector3::from(v.tangent) - cgmath::Vector3::unit_x()).magnitude() < 1e-5, "{:?}", v);
+            assert!((cgmath::Vector3::from(v.bitangent) - cgmath::Vector3::unit_y()).magnitude() < 1e-5, "{:?}", v);
+        }
+
+        // Mirroring u flips the tangent, the bitangent keeps pointing up the image
+        let mut mirrored = vertices.map(|v| vertex(v.position, [1.0 - v.tex_coords[0], v.tex_coords[1]]));
+        compute_tangents(&mut mirrored, &[0, 1, 2, 0, 2, 3]);
+        for v in &mirrored {
+            assert!((cgmath::Vector3::from(v.tangent) + cgmath::Vector3::unit_x()).magnitude() < 1e-5, "{:?}", v);
+            assert!((cgmath::Vector3::from(v.bitangent) - cgmath::Vector3::unit_y()).magnitude() < 1e-5, "{:?}", v);
+        }
+
+        // Degenerate texture coordinates still give a unit tangent perpendicular to the normal
+        let mut flat = vertices.map(|v| vertex(v.position, [0.0, 0.0]));
+        compute_tangents(&mut flat, &[0, 1, 2, 0, 2, 3]);
+        for v in &flat {
+            let tangent = cgmath::Vector3::from(v.tangent);
+            assert!((tangent.magnitude() - 1.0).abs() < 1e-5 && tangent.dot(cgmath::Vector3::from(v.normal)).abs() < 1e-5, "{:?}", v);
+        }
     }
 }
@@ -0,0 +1,326 @@
+use wgpu::{BindGroup, BindGroupLayout, Buffer, CommandEncoder, Device, PipelineLayout, Queue, RenderPipeline, SurfaceConfiguration, TextureView};
+use wgpu::util::DeviceExt;
+
+use anyhow::anyhow;
+
+use crate::texture::Texture;
+
+// Handle to a post-process pass added to the world
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PostProcessId(usize);
+
+// Handle to a color lookup table loaded with `World::load_color_lut`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LutId(usize);
+
+#[derive(Clone, Debug)]
+pub enum PostProcess {
+    // Darkens the screen towards the corners. The darkening starts at `radius`, 0 being the center
+    // and 1 the corners, and reaches `intensity` over `softness`
+    Vignette { intensity: f32, radius: f32, softness: f32 },
+    // Splits red and blue apart towards the edges of the screen like a cheap lens, `strength` is the
+    // offset at the edges as a fraction of the screen
+    ChromaticAberration { strength: f32 },
+    // Remaps the colors through a lookup table, blended over the original colors by `intensity`
+    ColorGrading { lut: LutId, intensity: f32 },
+    // A WGSL fragment shader providing `fs_main`, appended to `post.wgsl` which declares what it can
+    // read. `params` arrive in `post.params`
+    Custom { shader: String, params: [f32; 8] },
+}
+
+impl PostProcess {
+    fn source(&self) -> &str {
+        match self {
+            PostProcess::Vignette { .. } => include_str!("vignette.wgsl"),
+            PostProcess::ChromaticAberration { .. } => include_str!("chromatic_aberration.wgsl"),
+            PostProcess::ColorGrading { .. } => include_str!("color_grading.wgsl"),
+            PostProcess::Custom { shader, .. } => shader,
+        }
+    }
+
+    fn params(&self) -> [f32; 8] {
+        match *self {
+            PostProcess::Vignette { intensity, radius, softness } => [intensity, radius, softness, 0.0, 0.0, 0.0, 0.0, 0.0],
+            PostProcess::ChromaticAberration { strength } => [strength, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            // The table size is filled in once the table is known
+            PostProcess::ColorGrading { intensity, .. } => [intensity, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            PostProcess::Custom { params, .. } => params,
+        }
+    }
+
+    fn lut(&self) -> Option<LutId> {
+        match *self {
+            PostProcess::ColorGrading { lut, .. } => Some(lut),
+            _ => None,
+        }
+    }
+}
+
+struct Pass {
+    id: usize,
+    process: PostProcess,
+    buffer: Buffer,
+    pipeline: RenderPipeline,
+    // Reading the first and the second target
+    bind_groups: [BindGroup; 2],
+}
+
+pub struct Context {
+    passes: Vec<Pass>,
+    next_pass: usize,
+    // Lookup tables with their size
+    luts: Vec<(Texture, u32)>,
+    // Bound to passes without a table of their own
+    blank_lut: Texture,
+    layout: BindGroupLayout,
+    pipeline_layout: PipelineLayout,
+    format: wgpu::TextureFormat,
+    resolution: [f32; 2],
+    // Passes read one and write the other, the first one is where the passes start
+    pub targets: [Texture; 2],
+}
+
+impl Context {
+    pub fn new(device: &Device, queue: &Queue, config: &SurfaceConfiguration) -> Self {
+        let texture_entry = |binding, view_dimension| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture_entry(1, wgpu::TextureViewDimension::D2),
+                texture_entry(2, wgpu::TextureViewDimension::D2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                texture_entry(4, wgpu::TextureViewDimension::D3),
+            ],
+            label: Some("post_bind_group_layout"),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Post Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+
+        let white = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4])));
+        let blank_lut = Texture::from_lut_image(device, queue, &white, "blank_lut_texture").unwrap();
+
+        Context {
+            passes: Vec::new(),
+            next_pass: 0,
+            luts: Vec::new(),
+            blank_lut,
+            layout,
+            pipeline_layout,
+            format: config.format,
+            resolution: [config.width as f32, config.height as f32],
+            targets: Self::create_targets(device, config),
+        }
+    }
+
+    fn create_targets(device: &Device, config: &SurfaceConfiguration) -> [Texture; 2] {
+        [
+            Texture::create_target_texture(device, config.width, config.height, config.format, "post_texture"),
+            Texture::create_target_texture(device, config.width, config.height, config.format, "post_texture"),
+        ]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    pub fn load_lut(&mut self, device: &Device, queue: &Queue, img: &image::DynamicImage, label: &str) -> anyhow::Result<LutId> {
+        let texture = Texture::from_lut_image(device, queue, img, label)?;
+        self.luts.push((texture, img.height()));
+        Ok(LutId(self.luts.len() - 1))
+    }
+
+    fn uniform(&self, process: &PostProcess) -> PostUniform {
+        let mut params = process.params();
+        if let Some((_, size)) = process.lut().and_then(|lut| self.luts.get(lut.0)) {
+            params[1] = *size as f32;
+        }
+        PostUniform {
+            params,
+            resolution: self.resolution,
+            linear_color: self.format.describe().srgb as u32,
+            _padding: 0,
+        }
+    }
+
+    fn create_bind_groups(&self, device: &Device, buffer: &Buffer, process: &PostProcess, velocity: &Texture) -> [BindGroup; 2] {
+        let lut = process.lut().and_then(|lut| self.luts.get(lut.0)).map_or(&self.blank_lut, |(texture, _)| texture);
+        let bind_group = |source: &Texture| device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&velocity.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&source.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&lut.view),
+                },
+            ],
+            label: Some("post_bind_group"),
+        });
+        [bind_group(&self.targets[0]), bind_group(&self.targets[1])]
+    }
+
+    // Compile the pass's shader, reporting WGSL errors instead of failing on the device
+    async fn create_pipeline(&self, device: &Device, process: &PostProcess) -> anyhow::Result<RenderPipeline> {
+        if process.lut().is_some_and(|lut| lut.0 >= self.luts.len()) {
+            return Err(anyhow!("Color grading refers to a lookup table that was never loaded"));
+        }
+
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Post Shader"),
+            source: wgpu::ShaderSource::Wgsl(format!("{}\n{}", include_str!("post.wgsl"), process.source()).into()),
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Post Pipeline"),
+            layout: Some(&self.pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(self.format.into())],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+        match device.pop_error_scope().await {
+            Some(error) => Err(anyhow!("Post-process shader failed to build: {}", error)),
+            None => Ok(pipeline),
+        }
+    }
+
+    pub async fn add(&mut self, device: &Device, process: PostProcess, velocity: &Texture) -> anyhow::Result<PostProcessId> {
+        let pipeline = self.create_pipeline(device, &process).await?;
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Post Buffer"),
+            contents: bytemuck::cast_slice(&[self.uniform(&process)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_groups = self.create_bind_groups(device, &buffer, &process, velocity);
+
+        let id = self.next_pass;
+        self.next_pass += 1;
+        self.passes.push(Pass { id, process, buffer, pipeline, bind_groups });
+        Ok(PostProcessId(id))
+    }
+
+    pub fn get(&self, id: PostProcessId) -> Option<&PostProcess> {
+        self.passes.iter().find(|pass| pass.id == id.0).map(|pass| &pass.process)
+    }
+
+    // Replace a pass in place, returns false if it no longer exists. The shader is only rebuilt when it changed
+    pub async fn update(&mut self, device: &Device, queue: &Queue, id: PostProcessId, process: PostProcess, velocity: &Texture) -> anyhow::Result<bool> {
+        let Some(index) = self.passes.iter().position(|pass| pass.id == id.0) else { return Ok(false) };
+        let pass = &self.passes[index];
+        let pipeline = if pass.process.source() != process.source() || pass.process.lut() != process.lut() {
+            Some(self.create_pipeline(device, &process).await?)
+        } else {
+            None
+        };
+        let uniform = self.uniform(&process);
+        let bind_groups = self.create_bind_groups(device, &pass.buffer, &process, velocity);
+
+        let pass = &mut self.passes[index];
+        queue.write_buffer(&pass.buffer, 0, bytemuck::cast_slice(&[uniform]));
+        if let Some(pipeline) = pipeline {
+            pass.pipeline = pipeline;
+        }
+        pass.bind_groups = bind_groups;
+        pass.process = process;
+        Ok(true)
+    }
+
+    pub fn remove(&mut self, id: PostProcessId) -> Option<PostProcess> {
+        let index = self.passes.iter().position(|pass| pass.id == id.0)?;
+        Some(self.passes.remove(index).process)
+    }
+
+    // Recreate the targets at the new surface size, along with every pass's view of them
+    pub fn resize(&mut self, device: &Device, queue: &Queue, config: &SurfaceConfiguration, velocity: &Texture) {
+        self.targets = Self::create_targets(device, config);
+        self.resolution = [config.width as f32, config.height as f32];
+        for index in 0..self.passes.len() {
+            let pass = &self.passes[index];
+            let uniform = self.uniform(&pass.process);
+            let bind_groups = self.create_bind_groups(device, &pass.buffer, &pass.process, velocity);
+            queue.write_buffer(&pass.buffer, 0, bytemuck::cast_slice(&[uniform]));
+            self.passes[index].bind_groups = bind_groups;
+        }
+    }
+
+    // Run the passes in order starting from the first target, the last one writes into `view`
+    pub fn draw(&self, encoder: &mut CommandEncoder, view: &TextureView) {
+        for (index, pass) in self.passes.iter().enumerate() {
+            let target = if index + 1 == self.passes.len() { view } else { &self.targets[(index + 1) % 2].view };
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Post Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(&pass.pipeline);
+            render_pass.set_bind_group(0, &pass.bind_groups[index % 2], &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct PostUniform {
+    pub params: [f32; 8],
+    pub resolution: [f32; 2],
+    pub linear_color: u32,
+    pub _padding: u32,
+}
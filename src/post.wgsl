@@ -0,0 +1,68 @@
+// Shared by every post-process pass, the pass's own shader is appended to it and provides
+// `fn fs_main(in: VertexOutput) -> @location(0) vec4<f32>`
+
+// Matches `PostUniform` in post.rs
+struct PostProcess {
+    // Values of the pass, `PostProcess::Custom::params` for custom passes
+    params: array<vec4<f32>, 2>,
+    // Size of the target in pixels
+    resolution: vec2<f32>,
+    // Set when the color textures store linear values, otherwise they hold display values already
+    linear_color: u32,
+}
+@group(0) @binding(0)
+var<uniform> post: PostProcess;
+// Output of the previous pass, the tonemapped scene for the first one
+@group(0) @binding(1)
+var t_color: texture_2d<f32>;
+// Screen space motion since the previous frame in xy and linear depth in z, read through `scene_depth`
+@group(0) @binding(2)
+var t_velocity: texture_2d<f32>;
+@group(0) @binding(3)
+var s_linear: sampler;
+// Color grading table, a single white texel unless the pass was given one
+@group(0) @binding(4)
+var t_lut: texture_3d<f32>;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    // 0,0 in the top left corner of the screen
+    @location(0) uv: vec2<f32>,
+}
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let position = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u)) * 2.0 - 1.0;
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(position, 0.0, 1.0);
+    out.uv = position * vec2<f32>(0.5, -0.5) + 0.5;
+    return out;
+}
+
+fn scene_color(uv: vec2<f32>) -> vec4<f32> {
+    return textureSampleLevel(t_color, s_linear, uv, 0.0);
+}
+
+// Distance from the camera along the view direction, 65504 where nothing was drawn
+fn scene_depth(uv: vec2<f32>) -> f32 {
+    let size = vec2<i32>(post.resolution);
+    let texel = clamp(vec2<i32>(uv * post.resolution), vec2<i32>(0), size - 1);
+    return textureLoad(t_velocity, texel, 0).z;
+}
+
+// sRGB encoded values, as shown on the display
+fn to_display(color: vec3<f32>) -> vec3<f32> {
+    if (post.linear_color == 0u) {
+        return color;
+    }
+    let c = max(color, vec3<f32>(0.0));
+    return select(1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3<f32>(0.0031308));
+}
+
+fn from_display(color: vec3<f32>) -> vec3<f32> {
+    if (post.linear_color == 0u) {
+        return color;
+    }
+    return select(pow((color + 0.055) / 1.055, vec3<f32>(2.4)), color / 12.92, color <= vec3<f32>(0.04045));
+}
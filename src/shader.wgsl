@@ -4,18 +4,113 @@
 struct CameraUniform {
     view_pos: vec4<f32>,
     view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+    prev_view_proj: mat4x4<f32>,
+    jitter: vec4<f32>,
 };
 // We create variables for the bind groups
 // This is the "second" group we bound, so we access via `@group(1)`
 @group(1) @binding(0)
 var<uniform> camera: CameraUniform;
 
+// Image based lighting, matching `EnvironmentUniform` in environment.rs
+struct Environment {
+    intensity: f32,
+    // Mip level of the prefiltered map holding roughness 1
+    max_lod: f32,
+    enabled: u32,
+}
+@group(1) @binding(1)
+var<uniform> environment: Environment;
+@group(1) @binding(2)
+var t_irradiance: texture_cube<f32>;
+@group(1) @binding(3)
+var t_prefiltered: texture_cube<f32>;
+@group(1) @binding(4)
+var t_brdf_lut: texture_2d<f32>;
+@group(1) @binding(5)
+var s_environment: sampler;
+// Share of the ambient light reaching each pixel, measured on screen. Stays at 1 with SSAO off
+@group(1) @binding(6)
+var t_ambient_occlusion: texture_2d<f32>;
+
+// Matches `FogUniform` in fog.rs
+struct Fog {
+    color: vec3<f32>,
+    mode: u32,
+    start: f32,
+    end: f32,
+    density: f32,
+    height_density: f32,
+    height: f32,
+    height_falloff: f32,
+}
+@group(1) @binding(7)
+var<uniform> fog: Fog;
+
+// Fog modes, matching `FogMode` in fog.rs
+let FOG_LINEAR: u32 = 1u;
+let FOG_EXPONENTIAL: u32 = 2u;
+let FOG_EXPONENTIAL_SQUARED: u32 = 3u;
+
+// Light kinds, matching `LightUniform` in light.rs
+let LIGHT_POINT: u32 = 0u;
+let LIGHT_DIRECTIONAL: u32 = 1u;
+let LIGHT_SPOT: u32 = 2u;
+
 struct Light {
     position: vec3<f32>,
+    range: f32,
     color: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    inner_cos: f32,
+    outer_cos: f32,
+    // Point shadow slot, -1 without a cube shadow map
+    shadow_slot: i32,
+}
+struct Lights {
+    count: u32,
+    // Where the ambient light comes from, -1 without a main light
+    main_light: i32,
+    // `light::shader_source` fills in a runtime sized array, or a fixed one in a uniform buffer
+    lights: LIGHTS_ARRAY,
 }
 @group(2) @binding(0)
-var<uniform> light: Light;
+var<LIGHTS_ADDRESS_SPACE> lights: Lights;
+
+struct Shadow {
+    // One light matrix per cascade, only the first is used by non-directional lights
+    view_proj: array<mat4x4<f32>, 4>,
+    // Camera distance where each cascade ends
+    splits: vec4<f32>,
+    camera_forward: vec4<f32>,
+    // Index into `lights` of the light casting shadows, -1 when shadows are off
+    light_index: i32,
+    bias: f32,
+    texel_size: f32,
+    cascade_count: u32,
+    // Fraction of a cascade blended into the next
+    blend: f32,
+    debug: u32,
+}
+@group(3) @binding(0)
+var<uniform> shadow: Shadow;
+@group(3) @binding(1)
+var t_shadow: texture_depth_2d_array;
+@group(3) @binding(2)
+var s_shadow: sampler_comparison;
+
+struct PointShadow {
+    // Six faces per slot in +X, -X, +Y, -Y, +Z, -Z order
+    view_proj: array<mat4x4<f32>, 24>,
+    bias: f32,
+    texel_size: f32,
+}
+@group(3) @binding(3)
+var<uniform> point_shadow: PointShadow;
+@group(3) @binding(4)
+var t_point_shadow: texture_depth_2d_array;
 
 // This is the input from the vertex buffer we created
 // We get the properties from our Vertex struct here
@@ -25,6 +120,8 @@ struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) tex_coords: vec2<f32>,
     @location(2) normal: vec3<f32>,
+    @location(3) tangent: vec3<f32>,
+    @location(4) bitangent: vec3<f32>,
 };
 // The instance buffer
 struct InstanceInput {
@@ -46,6 +143,8 @@ struct VertexOutput {
     @location(0) tex_coords: vec2<f32>,
     @location(1) world_normal: vec3<f32>,
     @location(2) world_position: vec3<f32>,
+    @location(3) world_tangent: vec3<f32>,
+    @location(4) world_bitangent: vec3<f32>,
 };
 
 @vertex
@@ -70,7 +169,11 @@ fn vs_main(
     var out: VertexOutput;
     out.tex_coords = model.tex_coords;
 
-    out.world_normal = normal_matrix * model.normal;
+    // The normal matrix is the inverse-transpose of the model matrix, so scaled normals need renormalizing
+    out.world_normal = normalize(normal_matrix * model.normal);
+    // Tangents lie in the surface, so they follow the model matrix itself
+    out.world_tangent = normalize((model_matrix * vec4<f32>(model.tangent, 0.0)).xyz);
+    out.world_bitangent = normalize((model_matrix * vec4<f32>(model.bitangent, 0.0)).xyz);
     var world_position: vec4<f32> = model_matrix * vec4<f32>(model.position, 1.0);
     out.world_position = world_position.xyz;
 
@@ -87,27 +190,372 @@ fn vs_main(
 var t_diffuse: texture_2d<f32>;
 @group(0)@binding(1)
 var s_diffuse: sampler;
+@group(0) @binding(2)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(3)
+var s_normal: sampler;
+
+// Material constants, matching `MaterialUniform` in model.rs
+struct Material {
+    ambient: vec3<f32>,
+    shininess: f32,
+    diffuse: vec3<f32>,
+    dissolve: f32,
+    specular: vec3<f32>,
+    shading: u32,
+    emissive: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    occlusion_strength: f32,
+    alpha_mode: u32,
+    alpha_cutoff: f32,
+}
+@group(0) @binding(4)
+var<uniform> material: Material;
+// Metalness in blue and roughness in green, as in glTF
+@group(0) @binding(5)
+var t_metallic_roughness: texture_2d<f32>;
+@group(0) @binding(6)
+var s_metallic_roughness: sampler;
+@group(0) @binding(7)
+var t_occlusion: texture_2d<f32>;
+@group(0) @binding(8)
+var s_occlusion: sampler;
+@group(0) @binding(9)
+var t_emissive: texture_2d<f32>;
+@group(0) @binding(10)
+var s_emissive: sampler;
+
+// Shading models, matching `MaterialUniform` in model.rs
+let SHADING_PHONG: u32 = 0u;
+let SHADING_PBR: u32 = 1u;
+
+// Alpha modes, matching `MaterialUniform` in model.rs
+let ALPHA_BLEND: u32 = 0u;
+let ALPHA_OPAQUE: u32 = 1u;
+let ALPHA_MASK: u32 = 2u;
+
+let PI: f32 = 3.14159265;
+
+// Share of the main light's color that reaches surfaces indirectly, scaled by each material's ambient color
+let AMBIENT_LIGHT: f32 = 0.1;
+
+// Trowbridge-Reitz GGX normal distribution, the share of microfacets facing along the half vector
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// Schlick-GGX self shadowing of the microfacets, for one direction
+fn geometry_schlick_ggx(n_dot_x: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = r * r / 8.0;
+    return n_dot_x / (n_dot_x * (1.0 - k) + k);
+}
+
+// Smith's method combines the shadowing towards the light and the masking towards the viewer
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    return geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+}
+
+// Share of light reflected rather than refracted, rising to 1 at grazing angles
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Rough surfaces reflect less of the environment at grazing angles, their microfacets face every way
+fn fresnel_schlick_roughness(cos_theta: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32> {
+    return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Fraction of light reaching the position in one cascade, filtered over a 3x3 texel area (PCF) to soften the edges
+fn cascade_visibility(cascade: u32, world_position: vec3<f32>, n_dot_l: f32) -> f32 {
+    let clip = shadow.view_proj[cascade] * vec4<f32>(world_position, 1.0);
+    if (clip.w <= 0.0) {
+        return 1.0;
+    }
+    let ndc = clip.xyz / clip.w;
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    // Outside the shadow map nothing is known to block the light
+    if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || ndc.z > 1.0) {
+        return 1.0;
+    }
+
+    // Surfaces at a grazing angle to the light need more bias to avoid shadow acne
+    let bias = max(shadow.bias * (1.0 - n_dot_l), shadow.bias * 0.1);
+    var visibility = 0.0;
+    for (var x = -1; x <= 1; x = x + 1) {
+        for (var y = -1; y <= 1; y = y + 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * shadow.texel_size;
+            visibility = visibility + textureSampleCompareLevel(t_shadow, s_shadow, uv + offset, i32(cascade), ndc.z - bias);
+        }
+    }
+    return visibility / 9.0;
+}
+
+// Distance of the position in front of the camera, used to pick a cascade
+fn view_depth(world_position: vec3<f32>) -> f32 {
+    return dot(world_position - camera.view_pos.xyz, shadow.camera_forward.xyz);
+}
+
+fn cascade_index(depth: f32) -> u32 {
+    var cascade = 0u;
+    loop {
+        if (cascade + 1u >= shadow.cascade_count || depth <= shadow.splits[cascade]) {
+            break;
+        }
+        cascade = cascade + 1u;
+    }
+    return cascade;
+}
+
+fn shadow_visibility(world_position: vec3<f32>, n_dot_l: f32) -> f32 {
+    let depth = view_depth(world_position);
+    let cascade = cascade_index(depth);
+    var visibility = cascade_visibility(cascade, world_position, n_dot_l);
+
+    // Fade into the next cascade towards the end of this one to hide the seam
+    if (cascade + 1u < shadow.cascade_count) {
+        var start = 0.0;
+        if (cascade > 0u) {
+            start = shadow.splits[cascade - 1u];
+        }
+        let end = shadow.splits[cascade];
+        let blend_start = end - (end - start) * shadow.blend;
+        if (depth > blend_start) {
+            let t = (depth - blend_start) / max(end - blend_start, 0.0001);
+            visibility = mix(visibility, cascade_visibility(cascade + 1u, world_position, n_dot_l), t);
+        }
+    }
+    return visibility;
+}
+
+// Direction from the position towards the light
+fn light_direction(light: Light, world_position: vec3<f32>) -> vec3<f32> {
+    if (light.kind == LIGHT_DIRECTIONAL) {
+        return normalize(-light.direction);
+    }
+    return normalize(light.position - world_position);
+}
+
+// Share of the light left at the position after its range and, for spot lights, its cone
+fn light_attenuation(light: Light, world_position: vec3<f32>) -> f32 {
+    if (light.kind == LIGHT_DIRECTIONAL) {
+        return 1.0;
+    }
+
+    // Fade the light out smoothly as it approaches its range
+    let light_distance = distance(light.position, world_position);
+    let falloff = clamp(1.0 - pow(light_distance / light.range, 4.0), 0.0, 1.0);
+    var attenuation = falloff * falloff;
+
+    if (light.kind == LIGHT_SPOT) {
+        // Soften the cone edge between the inner and outer angle
+        let cos_angle = dot(-light_direction(light, world_position), normalize(light.direction));
+        attenuation = attenuation * smoothstep(light.outer_cos, light.inner_cos, cos_angle);
+    }
+    return attenuation;
+}
+
+// Fraction of a point light reaching the position, read from the cube face the light sees it through.
+// The maps store the distance to the light divided by its range rather than the projected depth
+fn point_visibility(light: Light, world_position: vec3<f32>, n_dot_l: f32) -> f32 {
+    let to_position = world_position - light.position;
+    let a = abs(to_position);
+    var face: u32;
+    if (a.x >= a.y && a.x >= a.z) {
+        face = select(1u, 0u, to_position.x > 0.0);
+    } else if (a.y >= a.z) {
+        face = select(3u, 2u, to_position.y > 0.0);
+    } else {
+        face = select(5u, 4u, to_position.z > 0.0);
+    }
+    let layer = u32(light.shadow_slot) * 6u + face;
+
+    let clip = point_shadow.view_proj[layer] * vec4<f32>(world_position, 1.0);
+    let uv = clip.xy / clip.w * vec2<f32>(0.5, -0.5) + 0.5;
+    let depth = length(to_position) / light.range;
+    if (depth > 1.0) {
+        return 1.0;
+    }
+
+    let bias = max(point_shadow.bias * (1.0 - n_dot_l), point_shadow.bias * 0.1);
+    var visibility = 0.0;
+    for (var x = -1; x <= 1; x = x + 1) {
+        for (var y = -1; y <= 1; y = y + 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * point_shadow.texel_size;
+            visibility = visibility + textureSampleCompareLevel(t_point_shadow, s_shadow, uv + offset, i32(layer), depth - bias);
+        }
+    }
+    return visibility / 9.0;
+}
+
+// Share of the surface hidden by fog between the camera and the given point
+fn fog_amount(world_position: vec3<f32>) -> f32 {
+    let to_surface = world_position - camera.view_pos.xyz;
+    let range = length(to_surface);
+
+    // Share of the light that makes it through
+    var transmittance = 1.0;
+    if (fog.mode == FOG_LINEAR) {
+        transmittance = 1.0 - clamp((range - fog.start) / (fog.end - fog.start), 0.0, 1.0);
+    } else if (fog.mode == FOG_EXPONENTIAL) {
+        transmittance = exp(-fog.density * range);
+    } else if (fog.mode == FOG_EXPONENTIAL_SQUARED) {
+        let thickness = fog.density * range;
+        transmittance = exp(-thickness * thickness);
+    }
+
+    if (fog.height_density > 0.0) {
+        // The density falls off exponentially with height, integrated along the ray from the camera
+        let camera_density = fog.height_density * exp(-fog.height_falloff * (camera.view_pos.y - fog.height));
+        let rise = fog.height_falloff * to_surface.y;
+        var average = 1.0;
+        if (abs(rise) > 0.0001) {
+            average = (1.0 - exp(-rise)) / rise;
+        }
+        transmittance = transmittance * exp(-camera_density * average * range);
+    }
+    return 1.0 - transmittance;
+}
+
+// Screen space motion since the previous frame in texture coordinates for temporal anti-aliasing,
+// followed by the distance along the view direction for post-processing
+fn velocity(world_position: vec3<f32>) -> vec4<f32> {
+    let current = camera.view_proj * vec4<f32>(world_position, 1.0);
+    let previous = camera.prev_view_proj * vec4<f32>(world_position, 1.0);
+    let delta = current.xy / current.w - camera.jitter.xy - previous.xy / previous.w;
+    return vec4<f32>(delta * vec2<f32>(0.5, -0.5), current.w, 0.0);
+}
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    @location(1) velocity: vec4<f32>,
+}
 
 @fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+fn fs_main(in: VertexOutput) -> FragmentOutput {
     // We use the special function `textureSample` to combine the texture data with coords
     let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
     
-    // We don't need (or want) much ambient light, so 0.1 is fine
-    let ambient_strength = 0.1;
-    let ambient_color = light.color * ambient_strength;
-
-    let light_dir = normalize(light.position - in.world_position);
+    // Interpolation between vertices shortens the normal
+    let vertex_normal = normalize(in.world_normal);
+    // Keep the interpolated tangent frame orthogonal before moving the normal map into world space
+    let tangent = normalize(in.world_tangent - vertex_normal * dot(vertex_normal, in.world_tangent));
+    let bitangent = normalize(cross(vertex_normal, tangent)) * sign(dot(cross(vertex_normal, tangent), in.world_bitangent));
+    let tangent_matrix = mat3x3<f32>(tangent, bitangent, vertex_normal);
+    let tangent_normal = textureSample(t_normal, s_normal, in.tex_coords).xyz * 2.0 - 1.0;
+    let world_normal = normalize(tangent_matrix * tangent_normal);
     let view_dir = normalize(camera.view_pos.xyz - in.world_position);
-    let half_dir = normalize(view_dir + light_dir);
 
-    let diffuse_strength = max(dot(in.world_normal, light_dir), 0.0);
-    let diffuse_color = light.color * diffuse_strength;
+    // Kd tints the diffuse texture, a material without one is just its Kd color
+    let base_color = object_color.xyz * material.diffuse;
+    let material_occlusion = mix(1.0, textureSample(t_occlusion, s_occlusion, in.tex_coords).r, material.occlusion_strength);
+    let occlusion = material_occlusion * textureLoad(t_ambient_occlusion, vec2<i32>(in.clip_position.xy), 0).r;
+
+    let metallic_roughness = textureSample(t_metallic_roughness, s_metallic_roughness, in.tex_coords);
+    let metallic = material.metallic * metallic_roughness.b;
+    // A perfectly smooth surface would focus a light into an infinitely small highlight
+    let roughness = max(material.roughness * metallic_roughness.g, 0.04);
+    // Dielectrics reflect about 4% of the light head on, metals reflect in their own color
+    let f0 = mix(vec3<f32>(0.04), base_color, metallic);
+    let n_dot_v = max(dot(world_normal, view_dir), 0.0001);
+
+    // Diffuse and ambient light are tinted by the base color, specular highlights keep the light's color.
+    // The ambient term only follows the main light, so adding lights doesn't wash the scene out, and
+    // the environment replaces it once one is loaded
+    var result = vec3<f32>(0.0);
+    if (lights.main_light >= 0 && environment.enabled == 0u) {
+        let main_light = lights.lights[lights.main_light];
+        result = main_light.color * AMBIENT_LIGHT * material.ambient * occlusion * light_attenuation(main_light, in.world_position);
+    }
+    var specular = vec3<f32>(0.0);
+    for (var i = 0u; i < lights.count; i = i + 1u) {
+        let light = lights.lights[i];
+
+        let light_dir = light_direction(light, in.world_position);
+        let attenuation = light_attenuation(light, in.world_position);
+
+        let half_dir = normalize(view_dir + light_dir);
+        let n_dot_l = max(dot(world_normal, light_dir), 0.0);
+
+        var visibility = 1.0;
+        if (light.shadow_slot >= 0) {
+            visibility = point_visibility(light, in.world_position, n_dot_l);
+        } else if (i32(i) == shadow.light_index) {
+            visibility = shadow_visibility(in.world_position, n_dot_l);
+        }
+
+        if (material.shading == SHADING_PBR) {
+            // Cook-Torrance, the light color counts as the irradiance of a surface facing the light
+            let n_dot_h = max(dot(world_normal, half_dir), 0.0);
+            let fresnel = fresnel_schlick(max(dot(half_dir, view_dir), 0.0), f0);
+            let specular_brdf = distribution_ggx(n_dot_h, roughness) * geometry_smith(n_dot_v, n_dot_l, roughness) * fresnel
+                / (4.0 * n_dot_v * max(n_dot_l, 0.0001));
+            // Metals have no diffuse reflection, what isn't reflected specularly is absorbed
+            let diffuse_share = (vec3<f32>(1.0) - fresnel) * (1.0 - metallic);
 
-    let specular_strength = pow(max(dot(in.world_normal, half_dir), 0.0), 32.0);
-    let specular_color = specular_strength * light.color;
+            result = result + light.color * diffuse_share * n_dot_l * visibility * attenuation;
+            specular = specular + light.color * specular_brdf * PI * n_dot_l * visibility * attenuation;
+        } else {
+            let diffuse_color = light.color * n_dot_l;
 
-    let result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
+            // An exponent of 0 would light the whole surface evenly
+            let specular_strength = pow(max(dot(world_normal, half_dir), 0.0), max(material.shininess, 1.0));
+            let specular_color = specular_strength * light.color * material.specular;
 
-    return vec4<f32>(result, object_color.a);
-}
\ No newline at end of file
+            result = result + diffuse_color * visibility * attenuation;
+            specular = specular + specular_color * visibility * attenuation;
+        }
+    }
+
+    if (environment.enabled != 0u) {
+        let irradiance = textureSampleLevel(t_irradiance, s_environment, world_normal, 0.0).rgb * environment.intensity;
+        if (material.shading == SHADING_PBR) {
+            // Split sum approximation, prefiltered light times the BRDF's scale and bias for F0
+            let fresnel = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+            let reflected = reflect(-view_dir, world_normal);
+            let prefiltered = textureSampleLevel(t_prefiltered, s_environment, reflected, roughness * environment.max_lod).rgb * environment.intensity;
+            let brdf = textureSampleLevel(t_brdf_lut, s_environment, vec2<f32>(n_dot_v, roughness), 0.0).rg;
+            result = result + irradiance * (vec3<f32>(1.0) - fresnel) * (1.0 - metallic) * occlusion;
+            specular = specular + prefiltered * (fresnel * brdf.x + brdf.y) * occlusion;
+        } else {
+            result = result + irradiance * material.ambient * occlusion;
+        }
+    }
+
+    let emissive = material.emissive * textureSample(t_emissive, s_emissive, in.tex_coords).rgb;
+    var color = result * base_color + specular + emissive;
+
+    // Tint each shadow cascade in its own color when debugging the splits. Only directional lights
+    // get more than one cascade, point and spot lights keep their colors
+    if (shadow.debug != 0u && shadow.cascade_count > 1u) {
+        var cascade_colors = array<vec3<f32>, 4>(
+            vec3<f32>(1.0, 0.3, 0.3),
+            vec3<f32>(0.3, 1.0, 0.3),
+            vec3<f32>(0.3, 0.3, 1.0),
+            vec3<f32>(1.0, 1.0, 0.3),
+        );
+        color = color * cascade_colors[cascade_index(view_depth(in.world_position))];
+    }
+
+    color = mix(color, fog.color, fog_amount(in.world_position));
+
+    // Opaque materials ignore their alpha, masked ones are either cut out or opaque
+    var alpha = object_color.a * material.dissolve;
+    if (material.alpha_mode == ALPHA_MASK) {
+        if (alpha < material.alpha_cutoff) {
+            discard;
+        }
+        alpha = 1.0;
+    } else if (material.alpha_mode == ALPHA_OPAQUE) {
+        alpha = 1.0;
+    }
+
+    var out: FragmentOutput;
+    out.color = vec4<f32>(color, alpha);
+    out.velocity = velocity(in.world_position);
+    return out;
+}
@@ -0,0 +1,606 @@
+use wgpu::{BindGroup, BindGroupLayout, Buffer, Device, Queue, RenderPipeline, TextureView};
+use wgpu::util::DeviceExt;
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, SquareMatrix, Vector3};
+
+use crate::camera::Camera;
+use crate::light::{Light, LightId};
+use crate::model::{ModelVertex, Vertex};
+use crate::instance::InstanceRaw;
+use crate::texture::Texture;
+
+// cgmath builds OpenGL style projections with a -1..1 depth range, wgpu expects 0..1
+#[rustfmt::skip]
+pub(crate) const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
+);
+
+// Upper limit for `ShadowSettings::cascades`, matching the cascade arrays in `shader.wgsl`
+pub const MAX_CASCADES: usize = 4;
+
+// Upper limit for `ShadowSettings::max_point_shadows`, matching the face array in `shader.wgsl`
+pub const MAX_POINT_SHADOWS: usize = 4;
+
+// The GL backend turns square arrays with a multiple of six layers into cube maps, which can't be
+// sampled as a plain array, so the point shadow faces get one spare layer
+const POINT_SHADOW_LAYERS: usize = MAX_POINT_SHADOWS * 6 + 1;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowSettings {
+    pub enabled: bool,
+    // Width and height of the shadow map in texels
+    pub resolution: u32,
+    // Depth offset applied before comparing against the shadow map, grows on surfaces at a grazing angle to the light
+    pub depth_bias: f32,
+    // Width of the area around the camera target covered by a single shadow map, for directional
+    // lights with cascades this is how far outside the view casters are still picked up
+    pub extent: f32,
+    // Number of shadow maps the camera frustum is split into for directional lights, 1 to MAX_CASCADES
+    pub cascades: u32,
+    // Blend between uniform (0.0) and logarithmic (1.0) cascade splits
+    pub cascade_split_lambda: f32,
+    // Fraction of each cascade's depth range blended into the next one to hide the seams
+    pub cascade_blend: f32,
+    // Tint each cascade in a different color to inspect the splits
+    pub debug_cascades: bool,
+    // Width and height of each of the six faces of a point light shadow map in texels
+    pub point_resolution: u32,
+    // How many point lights get a shadow map each frame, 0 to MAX_POINT_SHADOWS. The main light
+    // goes first, the remaining slots go to the shadow casting point lights closest to the camera
+    pub max_point_shadows: u32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings {
+            enabled: true,
+            resolution: 2048,
+            depth_bias: 0.005,
+            extent: 20.0,
+            cascades: 4,
+            cascade_split_lambda: 0.75,
+            cascade_blend: 0.1,
+            debug_cascades: false,
+            point_resolution: 512,
+            max_point_shadows: 4,
+        }
+    }
+}
+
+pub struct Context {
+    pub settings: ShadowSettings,
+    pub uniform: ShadowUniform,
+    pub buffer: Buffer,
+    // Depth array with one layer per cascade
+    pub texture: Texture,
+    // Render target views of the individual layers
+    pub layer_views: Vec<TextureView>,
+    // Layout and bind group sampling the shadow map in the main shader
+    pub layout: BindGroupLayout,
+    pub bind_group: BindGroup,
+    // One light matrix buffer and bind group per cascade, used while rendering the shadow map itself
+    pub pass_buffers: Vec<Buffer>,
+    pub pass_bind_groups: Vec<BindGroup>,
+    pub render_pipeline: RenderPipeline,
+    pub point_uniform: PointShadowUniform,
+    pub point_buffer: Buffer,
+    // Depth array with six layers per point light, one for each cube face
+    pub point_texture: Texture,
+    pub point_layer_views: Vec<TextureView>,
+    pub point_pass_buffers: Vec<Buffer>,
+    pub point_pass_bind_groups: Vec<BindGroup>,
+    // Writes the distance to the light instead of the projected depth
+    pub point_render_pipeline: RenderPipeline,
+    // Light buffer index, position and range of the light rendered into each point shadow slot this frame
+    point_lights: Vec<(usize, Point3<f32>, f32)>,
+}
+
+impl Context {
+    pub fn new(device: &Device, settings: ShadowSettings) -> Self {
+        let uniform = ShadowUniform::new();
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Shadow Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("shadow_bind_group_layout"),
+        });
+
+        let pass_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("shadow_pass_bind_group_layout"),
+        });
+
+        let create_pass_bind_group = |buffer: &Buffer| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &pass_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                }],
+                label: Some("shadow_pass_bind_group"),
+            })
+        };
+
+        let pass_buffers = Self::create_pass_buffers(device, MAX_CASCADES, "Shadow Pass Buffer");
+        let pass_bind_groups = pass_buffers.iter().map(create_pass_bind_group).collect::<Vec<_>>();
+        let point_pass_buffers = Self::create_pass_buffers(device, MAX_POINT_SHADOWS * 6, "Point Shadow Pass Buffer");
+        let point_pass_bind_groups = point_pass_buffers.iter().map(create_pass_bind_group).collect::<Vec<_>>();
+
+        let point_uniform = PointShadowUniform::new();
+        let point_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Point Shadow Buffer"),
+            contents: bytemuck::cast_slice(&[point_uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let texture = Texture::create_shadow_texture(device, settings.resolution, MAX_CASCADES as u32, "shadow_texture");
+        let layer_views = Self::create_layer_views(&texture, MAX_CASCADES);
+        let point_texture = Texture::create_shadow_texture(device, settings.point_resolution, POINT_SHADOW_LAYERS as u32, "point_shadow_texture");
+        let point_layer_views = Self::create_layer_views(&point_texture, MAX_POINT_SHADOWS * 6);
+        let bind_group = Self::create_bind_group(device, &layout, &buffer, &texture, &point_buffer, &point_texture);
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Shadow Pipeline Layout"),
+            bind_group_layouts: &[&pass_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Shadow Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shadow.wgsl").into()),
+        });
+        let create_pipeline = |label: &str, fragment: Option<wgpu::FragmentState>| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[ModelVertex::desc(), InstanceRaw::desc()],
+                },
+                fragment,
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: Some(wgpu::Face::Back),
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    unclipped_depth: false,
+                    conservative: false,
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: Texture::DEPTH_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::LessEqual,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+        // Depth only, there is no color target to write to
+        let render_pipeline = create_pipeline("Shadow Pipeline", None);
+        let point_render_pipeline = create_pipeline("Point Shadow Pipeline", Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_point",
+            targets: &[],
+        }));
+
+        Context {
+            settings,
+            uniform,
+            buffer,
+            texture,
+            layer_views,
+            layout,
+            bind_group,
+            pass_buffers,
+            pass_bind_groups,
+            render_pipeline,
+            point_uniform,
+            point_buffer,
+            point_texture,
+            point_layer_views,
+            point_pass_buffers,
+            point_pass_bind_groups,
+            point_render_pipeline,
+            point_lights: Vec::new(),
+        }
+    }
+
+    fn create_pass_buffers(device: &Device, count: usize, label: &str) -> Vec<Buffer> {
+        (0..count).map(|i| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} {}", label, i)),
+                contents: bytemuck::cast_slice(&[ShadowPassUniform::new(Matrix4::identity(), [0.0; 4])]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            })
+        }).collect()
+    }
+
+    fn create_layer_views(texture: &Texture, layers: usize) -> Vec<TextureView> {
+        (0..layers as u32).map(|layer| {
+            texture.texture.create_view(&wgpu::TextureViewDescriptor {
+                label: Some("shadow_layer_view"),
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: std::num::NonZeroU32::new(1),
+                ..Default::default()
+            })
+        }).collect()
+    }
+
+    fn create_bind_group(device: &Device, layout: &BindGroupLayout, buffer: &Buffer, texture: &Texture, point_buffer: &Buffer, point_texture: &Texture) -> BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: point_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&point_texture.view),
+                },
+            ],
+            label: Some("shadow_bind_group"),
+        })
+    }
+
+    // Apply new settings, recreating the shadow maps if their resolution changed
+    pub fn set_settings(&mut self, device: &Device, mut settings: ShadowSettings) {
+        settings.cascades = settings.cascades.clamp(1, MAX_CASCADES as u32);
+        settings.max_point_shadows = settings.max_point_shadows.min(MAX_POINT_SHADOWS as u32);
+        // Empty depth textures or ones past the device limit can't be created
+        let max_resolution = device.limits().max_texture_dimension_2d;
+        settings.resolution = settings.resolution.clamp(1, max_resolution);
+        settings.point_resolution = settings.point_resolution.clamp(1, max_resolution);
+        if settings.resolution != self.settings.resolution {
+            self.texture = Texture::create_shadow_texture(device, settings.resolution, MAX_CASCADES as u32, "shadow_texture");
+            self.layer_views = Self::create_layer_views(&self.texture, MAX_CASCADES);
+        }
+        if settings.point_resolution != self.settings.point_resolution {
+            self.point_texture = Texture::create_shadow_texture(device, settings.point_resolution, POINT_SHADOW_LAYERS as u32, "point_shadow_texture");
+            self.point_layer_views = Self::create_layer_views(&self.point_texture, MAX_POINT_SHADOWS * 6);
+        }
+        if settings.resolution != self.settings.resolution || settings.point_resolution != self.settings.point_resolution {
+            self.bind_group = Self::create_bind_group(device, &self.layout, &self.buffer, &self.texture, &self.point_buffer, &self.point_texture);
+        }
+        self.settings = settings;
+    }
+
+    // Pick the point lights that get a cube shadow map this frame, returning their light buffer indices.
+    // The main light always goes first, the rest of the budget goes to the casters closest to `eye`
+    pub fn select_point_lights<'a>(&mut self, lights: impl Iterator<Item = (LightId, &'a Light)>, main_light: LightId, eye: Point3<f32>) -> Vec<usize> {
+        let budget = if self.settings.enabled { self.settings.max_point_shadows as usize } else { 0 };
+        let mut candidates = lights.enumerate().filter_map(|(index, (id, light))| match light {
+            Light::Point(l) if l.cast_shadows || id == main_light => {
+                let position = Point3::from(l.position.position());
+                Some((id != main_light, (position - eye).magnitude2(), (index, position, l.range)))
+            }
+            _ => None,
+        }).collect::<Vec<_>>();
+        candidates.sort_by(|a, b| (a.0, a.1).partial_cmp(&(b.0, b.1)).unwrap_or(std::cmp::Ordering::Equal));
+        self.point_lights = candidates.into_iter().take(budget).map(|(_, _, light)| light).collect();
+        self.point_lights.iter().map(|(index, _, _)| *index).collect()
+    }
+
+    // Point the shadow maps at `light`, which is stored at `light_index` in the light buffer, and upload them
+    pub fn update(&mut self, queue: &Queue, light: Option<(usize, &Light)>, camera: &Camera) {
+        let forward = (camera.target - camera.eye).normalize();
+        self.uniform.camera_forward = forward.extend(0.0).into();
+        self.uniform.bias = self.settings.depth_bias;
+        self.uniform.texel_size = 1.0 / self.settings.resolution as f32;
+        self.uniform.blend = self.settings.cascade_blend;
+        self.uniform.debug = self.settings.debug_cascades as u32;
+
+        let (index, light) = match light {
+            Some(light) if self.settings.enabled => light,
+            _ => {
+                self.uniform.light_index = -1;
+                self.uniform.cascade_count = 0;
+                queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+                return;
+            }
+        };
+        self.uniform.light_index = index as i32;
+
+        let matrices = match light {
+            Light::Directional(l) if self.settings.cascades > 1 => {
+                let direction = Vector3::from(l.direction.position()).normalize();
+                let splits = cascade_splits(camera.znear, camera.zfar, self.settings.cascades as usize, self.settings.cascade_split_lambda);
+                let mut near = camera.znear;
+                let mut matrices = Vec::new();
+                for (i, far) in splits.iter().enumerate() {
+                    self.uniform.splits[i] = *far;
+                    matrices.push(cascade_view_proj(camera, near, *far, direction, self.settings.extent, self.settings.resolution));
+                    near = *far;
+                }
+                matrices
+            }
+            _ => {
+                self.uniform.splits[0] = f32::MAX;
+                vec![light_view_proj(light, camera.target, self.settings.extent)]
+            }
+        };
+
+        self.uniform.cascade_count = matrices.len() as u32;
+        for (i, matrix) in matrices.into_iter().enumerate() {
+            self.uniform.view_proj[i] = matrix.into();
+            queue.write_buffer(&self.pass_buffers[i], 0, bytemuck::cast_slice(&[ShadowPassUniform::new(matrix, [0.0; 4])]));
+        }
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+
+    // Aim the six faces of every selected point light's shadow map and upload them
+    pub fn update_point(&mut self, queue: &Queue) {
+        self.point_uniform.bias = self.settings.depth_bias;
+        self.point_uniform.texel_size = 1.0 / self.settings.point_resolution as f32;
+
+        for (slot, (_, position, range)) in self.point_lights.iter().copied().enumerate() {
+            for (face, matrix) in cube_view_proj(position, range).into_iter().enumerate() {
+                let layer = slot * 6 + face;
+                self.point_uniform.view_proj[layer] = matrix.into();
+                let pass = ShadowPassUniform::new(matrix, [position.x, position.y, position.z, range]);
+                queue.write_buffer(&self.point_pass_buffers[layer], 0, bytemuck::cast_slice(&[pass]));
+            }
+        }
+        queue.write_buffer(&self.point_buffer, 0, bytemuck::cast_slice(&[self.point_uniform]));
+    }
+
+    pub fn cascade_count(&self) -> usize {
+        self.uniform.cascade_count as usize
+    }
+
+    pub fn point_count(&self) -> usize {
+        self.point_lights.len()
+    }
+}
+
+// Far distance of each cascade, blending a logarithmic split (even texel density) with a uniform one
+pub(crate) fn cascade_splits(near: f32, far: f32, cascades: usize, lambda: f32) -> Vec<f32> {
+    (1..=cascades).map(|i| {
+        let t = i as f32 / cascades as f32;
+        let log = near * (far / near).powf(t);
+        let uniform = near + (far - near) * t;
+        lambda * log + (1.0 - lambda) * uniform
+    }).collect()
+}
+
+// Fit an orthographic light projection around the slice of the camera frustum between `near` and `far`
+fn cascade_view_proj(camera: &Camera, near: f32, far: f32, direction: Vector3<f32>, extent: f32, resolution: u32) -> Matrix4<f32> {
+    let forward = (camera.target - camera.eye).normalize();
+    let right = forward.cross(camera.up).normalize();
+    let up = right.cross(forward);
+    let tan_y = (camera.fovy.to_radians() / 2.0).tan();
+    let tan_x = tan_y * camera.aspect;
+
+    let corners = [near, far].into_iter().flat_map(|d| {
+        let center = camera.eye + forward * d;
+        [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+            .map(|(x, y)| center + right * (x * tan_x * d) + up * (y * tan_y * d))
+    }).collect::<Vec<_>>();
+
+    let center = corners.iter().fold(Vector3::new(0.0, 0.0, 0.0), |sum, c| sum + c.to_vec()) / corners.len() as f32;
+    // A bounding sphere keeps the projection size constant while the camera turns, avoiding shimmering
+    let radius = corners.iter().map(|c| (c.to_vec() - center).magnitude()).fold(0.0, f32::max);
+
+    let light_up = if direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+    let rotation = Matrix4::look_to_rh(Point3::new(0.0, 0.0, 0.0), direction, light_up);
+
+    // Snap the center to whole shadow map texels so edges don't crawl as the camera moves
+    let texel = radius * 2.0 / resolution as f32;
+    let snapped = (rotation * center.extend(1.0)).truncate().map(|v| (v / texel).floor() * texel);
+    let center = (rotation.invert().unwrap() * snapped.extend(1.0)).truncate();
+
+    // Back the light up past the slice so casters outside the view still land in the map
+    let eye = Point3::from_vec(center - direction * (radius + extent));
+    let view = Matrix4::look_to_rh(eye, direction, light_up);
+    OPENGL_TO_WGPU_MATRIX * cgmath::ortho(-radius, radius, -radius, radius, 0.0, radius * 2.0 + extent) * view
+}
+
+// Build the matrix the light sees the scene through, a directional light covers `extent` around `target`
+pub(crate) fn light_view_proj(light: &Light, target: Point3<f32>, extent: f32) -> Matrix4<f32> {
+    let look = |eye: Point3<f32>, direction: Vector3<f32>| {
+        // Avoid a degenerate view matrix when looking straight up or down
+        let up = if direction.normalize().y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+        Matrix4::look_to_rh(eye, direction, up)
+    };
+
+    match light {
+        Light::Directional(l) => {
+            let direction = Vector3::from(l.direction.position()).normalize();
+            let view = look(target - direction * extent, direction);
+            let half = extent / 2.0;
+            OPENGL_TO_WGPU_MATRIX * cgmath::ortho(-half, half, -half, half, 0.1, extent * 2.0) * view
+        }
+        Light::Spot(l) => {
+            let view = look(Point3::from(l.position.position()), l.direction.position().into());
+            let fovy = (l.outer_angle * 2.0).clamp(1.0, 170.0);
+            OPENGL_TO_WGPU_MATRIX * cgmath::perspective(cgmath::Deg(fovy), 1.0, 0.1, l.range) * view
+        }
+        Light::Point(l) => {
+            // Only used without a point shadow budget, a single map can only face one way so look at the center of the scene
+            let eye = Point3::from(l.position.position());
+            let view = look(eye, target - eye);
+            OPENGL_TO_WGPU_MATRIX * cgmath::perspective(cgmath::Deg(90.0), 1.0, 0.1, l.range) * view
+        }
+    }
+}
+
+// One 90 degree projection per cube face, in the +X, -X, +Y, -Y, +Z, -Z order `shader.wgsl` picks faces in
+pub(crate) fn cube_view_proj(position: Point3<f32>, range: f32) -> [Matrix4<f32>; 6] {
+    let projection = OPENGL_TO_WGPU_MATRIX * cgmath::perspective(cgmath::Deg(90.0), 1.0, 0.05, range);
+    [
+        (Vector3::unit_x(), -Vector3::unit_y()),
+        (-Vector3::unit_x(), -Vector3::unit_y()),
+        (Vector3::unit_y(), Vector3::unit_z()),
+        (-Vector3::unit_y(), -Vector3::unit_z()),
+        (Vector3::unit_z(), -Vector3::unit_y()),
+        (-Vector3::unit_z(), -Vector3::unit_y()),
+    ].map(|(direction, up)| projection * Matrix4::look_to_rh(position, direction, up))
+}
+
+// Per pass data while rendering a shadow map, the light position is only read by the point light pipeline
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct ShadowPassUniform {
+    pub view_proj: [[f32; 4]; 4],
+    // xyz is the light position, w its range
+    pub light_position: [f32; 4],
+}
+
+impl ShadowPassUniform {
+    pub fn new(view_proj: Matrix4<f32>, light_position: [f32; 4]) -> Self {
+        ShadowPassUniform { view_proj: view_proj.into(), light_position }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct PointShadowUniform {
+    // Six faces per shadow slot, the slot of each light is stored in the light buffer
+    pub view_proj: [[[f32; 4]; 4]; MAX_POINT_SHADOWS * 6],
+    pub bias: f32,
+    pub texel_size: f32,
+    pub _padding: [u32; 2],
+}
+
+impl PointShadowUniform {
+    pub fn new() -> Self {
+        PointShadowUniform {
+            view_proj: [Matrix4::identity().into(); MAX_POINT_SHADOWS * 6],
+            bias: 0.0,
+            texel_size: 0.0,
+            _padding: [0; 2],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct ShadowUniform {
+    pub view_proj: [[[f32; 4]; 4]; MAX_CASCADES],
+    // Camera distance where each cascade ends
+    pub splits: [f32; MAX_CASCADES],
+    pub camera_forward: [f32; 4],
+    // Index of the shadow casting light in the light buffer, -1 when shadows are off
+    pub light_index: i32,
+    pub bias: f32,
+    pub texel_size: f32,
+    pub cascade_count: u32,
+    pub blend: f32,
+    pub debug: u32,
+    pub _padding: [u32; 2],
+}
+
+impl ShadowUniform {
+    pub fn new() -> Self {
+        ShadowUniform {
+            view_proj: [Matrix4::identity().into(); MAX_CASCADES],
+            splits: [0.0; MAX_CASCADES],
+            camera_forward: [0.0; 4],
+            light_index: -1,
+            bias: 0.0,
+            texel_size: 0.0,
+            cascade_count: 0,
+            blend: 0.0,
+            debug: 0,
+            _padding: [0; 2],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cascade_splits_blend() {
+        let close = |a: &[f32], b: &[f32]| a.len() == b.len() && a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-3);
+
+        assert!(close(&cascade_splits(1.0, 100.0, 4, 0.0), &[25.75, 50.5, 75.25, 100.0]));
+        assert!(close(&cascade_splits(1.0, 100.0, 2, 1.0), &[10.0, 100.0]));
+        assert!(close(&cascade_splits(1.0, 100.0, 2, 0.5), &[30.25, 100.0]));
+        assert!(close(&cascade_splits(0.1, 50.0, 1, 0.75), &[50.0]));
+
+        // Every cascade ends further than the one before it, the last one at the far plane
+        let splits = cascade_splits(0.1, 100.0, MAX_CASCADES, 0.75);
+        assert!(splits.windows(2).all(|w| w[0] < w[1]));
+        assert!((splits[MAX_CASCADES - 1] - 100.0).abs() < 1e-3);
+    }
+}
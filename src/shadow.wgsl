@@ -0,0 +1,52 @@
+// Vertex shader
+
+// Renders the scene depth as seen from a shadow casting light, once per cascade or cube face
+
+struct ShadowPass {
+    view_proj: mat4x4<f32>,
+    // xyz is the light position, w its range, only set for point lights
+    light_position: vec4<f32>,
+}
+@group(0) @binding(0)
+var<uniform> shadow_pass: ShadowPass;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+};
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec3<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    var out: VertexOutput;
+    out.clip_position = shadow_pass.view_proj * world_position;
+    out.world_position = world_position.xyz;
+    return out;
+}
+
+// Fragment shader
+
+// Point light maps store the distance to the light over its range, so every cube face compares the same way
+@fragment
+fn fs_point(in: VertexOutput) -> @builtin(frag_depth) f32 {
+    return length(in.world_position - shadow_pass.light_position.xyz) / shadow_pass.light_position.w;
+}
@@ -0,0 +1,236 @@
+use wgpu::{BindGroup, BindGroupLayout, Buffer, Device, PipelineLayout, Queue, RenderPipeline};
+use wgpu::util::DeviceExt;
+
+use crate::color::Color;
+use crate::texture::Texture;
+use crate::EnvironmentContext;
+
+// Largest face of a cube map converted from an equirectangular sky
+const MAX_SKY_SIZE: u32 = 2048;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Sky {
+    // Nothing is drawn behind the scene, it keeps the clear color
+    None,
+    // Blends from the horizon color up to the zenith, the ground color covers everything below the horizon
+    Gradient { zenith: Color, horizon: Color, ground: Color },
+    // The cube map loaded with `World::set_sky_cube_map` or `World::set_sky_equirectangular`
+    CubeMap,
+    // The environment lighting the scene, loaded with `World::set_environment`
+    Environment,
+}
+
+pub struct Context {
+    pub sky: Sky,
+    // Color behind the scene when there is no sky
+    pub clear_color: Color,
+    uniform: SkyUniform,
+    buffer: Buffer,
+    cube: Texture,
+    layout: BindGroupLayout,
+    pub bind_group: BindGroup,
+    pipeline_layout: PipelineLayout,
+    color_format: wgpu::TextureFormat,
+    pub render_pipeline: RenderPipeline,
+}
+
+impl Context {
+    pub fn new(device: &Device, camera_layout: &BindGroupLayout, environment: &EnvironmentContext, color_format: wgpu::TextureFormat, sample_count: u32) -> Self {
+        let uniform = SkyUniform::default();
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sky Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let cube_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::Cube,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                cube_entry(1),
+                cube_entry(2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("sky_bind_group_layout"),
+        });
+
+        // Black until a sky texture is loaded
+        let cube = Texture::create_cube_texture(device, 1, 1, "sky_texture");
+        let bind_group = Self::create_bind_group(device, &layout, &buffer, &cube, environment);
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Sky Pipeline Layout"),
+            bind_group_layouts: &[camera_layout, &layout],
+            push_constant_ranges: &[],
+        });
+        let render_pipeline = Self::create_pipeline(device, &pipeline_layout, color_format, sample_count);
+
+        Context {
+            sky: Sky::None,
+            clear_color: Color::new(0.1, 0.2, 0.3),
+            uniform,
+            buffer,
+            cube,
+            layout,
+            bind_group,
+            pipeline_layout,
+            color_format,
+            render_pipeline,
+        }
+    }
+
+    fn create_pipeline(device: &Device, layout: &PipelineLayout, color_format: wgpu::TextureFormat, sample_count: u32) -> RenderPipeline {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Sky Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("sky.wgsl").into()),
+        });
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Sky Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(color_format.into()), Some(Texture::VELOCITY_FORMAT.into())],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            // Drawn first without touching the depth buffer, so all geometry ends up in front of it
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
+            multiview: None,
+        })
+    }
+
+    // Rebuild the pipeline for a main pass with a different number of samples per pixel
+    pub fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        self.render_pipeline = Self::create_pipeline(device, &self.pipeline_layout, self.color_format, sample_count);
+    }
+
+    fn create_bind_group(device: &Device, layout: &BindGroupLayout, buffer: &Buffer, cube: &Texture, environment: &EnvironmentContext) -> BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&cube.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&environment.cube.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&cube.sampler),
+                },
+            ],
+            label: Some("sky_bind_group"),
+        })
+    }
+
+    pub fn set_sky(&mut self, queue: &Queue, sky: Sky) {
+        self.sky = sky;
+        self.uniform = match sky {
+            Sky::Gradient { zenith, horizon, ground } => SkyUniform {
+                zenith: zenith.color(),
+                kind: SkyUniform::GRADIENT,
+                horizon: horizon.color(),
+                ground: ground.color(),
+                ..SkyUniform::default()
+            },
+            Sky::CubeMap => SkyUniform { kind: SkyUniform::CUBE_MAP, ..self.uniform },
+            Sky::Environment => SkyUniform { kind: SkyUniform::ENVIRONMENT, ..self.uniform },
+            Sky::None => self.uniform,
+        };
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+
+    // Replace the sky cube map and show it
+    fn set_cube(&mut self, device: &Device, queue: &Queue, cube: Texture, environment: &EnvironmentContext) {
+        self.cube = cube;
+        self.bind_group = Self::create_bind_group(device, &self.layout, &self.buffer, &self.cube, environment);
+        self.set_sky(queue, Sky::CubeMap);
+    }
+
+    pub fn load_cube_map(&mut self, device: &Device, queue: &Queue, faces: &[image::DynamicImage; 6], environment: &EnvironmentContext) -> anyhow::Result<()> {
+        let cube = Texture::from_cube_images(device, queue, faces, "sky_texture")?;
+        self.set_cube(device, queue, cube, environment);
+        Ok(())
+    }
+
+    pub fn load_equirectangular(&mut self, device: &Device, queue: &Queue, img: &image::DynamicImage, environment: &EnvironmentContext) -> anyhow::Result<()> {
+        let source = Texture::from_hdr_image(device, queue, img, Some("sky_equirectangular_texture"))?;
+        // A face covers a quarter of the image's width
+        let size = (img.width() / 4).next_power_of_two().clamp(1, MAX_SKY_SIZE);
+        let cube = Texture::create_cube_texture(device, size, size.ilog2() + 1, "sky_texture");
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Sky Encoder") });
+        environment.render_equirectangular(device, &mut encoder, &source, img.width(), &cube, size);
+        queue.submit(std::iter::once(encoder.finish()));
+
+        self.set_cube(device, queue, cube, environment);
+        Ok(())
+    }
+
+    pub fn clear_color(&self) -> wgpu::Color {
+        let [r, g, b] = self.clear_color.color();
+        wgpu::Color { r: r as f64, g: g as f64, b: b as f64, a: 1.0 }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct SkyUniform {
+    pub zenith: [f32; 3],
+    pub kind: u32,
+    pub horizon: [f32; 3],
+    pub _padding: u32,
+    pub ground: [f32; 3],
+    pub _padding2: u32,
+}
+
+impl SkyUniform {
+    // Matches the sky kinds in `sky.wgsl`
+    pub const GRADIENT: u32 = 0;
+    pub const CUBE_MAP: u32 = 1;
+    pub const ENVIRONMENT: u32 = 2;
+}
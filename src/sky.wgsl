@@ -0,0 +1,86 @@
+// Vertex shader
+
+// Draws the sky behind the scene, a direction is looked up for every pixel the geometry doesn't cover
+
+struct Camera {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+    prev_view_proj: mat4x4<f32>,
+    jitter: vec4<f32>,
+}
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+
+// Sky kinds, matching `SkyUniform` in sky.rs
+let SKY_GRADIENT: u32 = 0u;
+let SKY_CUBE_MAP: u32 = 1u;
+let SKY_ENVIRONMENT: u32 = 2u;
+
+// Depth of everything behind the scene, the largest half float. Matches `Texture::FAR_DEPTH`
+let FAR_DEPTH: f32 = 65504.0;
+
+struct Sky {
+    zenith: vec3<f32>,
+    kind: u32,
+    horizon: vec3<f32>,
+    ground: vec3<f32>,
+}
+@group(1) @binding(0)
+var<uniform> sky: Sky;
+@group(1) @binding(1)
+var t_sky: texture_cube<f32>;
+@group(1) @binding(2)
+var t_environment: texture_cube<f32>;
+@group(1) @binding(3)
+var s_sky: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+}
+
+// A single triangle covering the whole screen
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let position = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u)) * 2.0 - 1.0;
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(position, 0.0, 1.0);
+    out.ndc = position;
+    return out;
+}
+
+// Fragment shader
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    @location(1) velocity: vec4<f32>,
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> FragmentOutput {
+    // Any point along the pixel's ray works, the far plane is always in front of the camera
+    let far = camera.inv_view_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+    let direction = normalize(far.xyz / far.w - camera.view_pos.xyz);
+
+    var color: vec3<f32>;
+    if (sky.kind == SKY_CUBE_MAP) {
+        color = textureSampleLevel(t_sky, s_sky, direction, 0.0).rgb;
+    } else if (sky.kind == SKY_ENVIRONMENT) {
+        color = textureSampleLevel(t_environment, s_sky, direction, 0.0).rgb;
+    } else if (direction.y >= 0.0) {
+        color = mix(sky.horizon, sky.zenith, sqrt(direction.y));
+    } else {
+        // The ground fades in just below the horizon
+        color = mix(sky.horizon, sky.ground, clamp(-direction.y * 10.0, 0.0, 1.0));
+    }
+
+    // The sky is infinitely far away, only turning the camera moves it
+    let previous = camera.prev_view_proj * vec4<f32>(direction, 0.0);
+    let delta = in.ndc - camera.jitter.xy - previous.xy / previous.w;
+
+    var out: FragmentOutput;
+    out.color = vec4<f32>(color, 1.0);
+    out.velocity = vec4<f32>(delta * vec2<f32>(0.5, -0.5), FAR_DEPTH, 0.0);
+    return out;
+}
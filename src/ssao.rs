@@ -0,0 +1,452 @@
+use std::num::NonZeroU32;
+
+use cgmath::{InnerSpace, SquareMatrix};
+use wgpu::{BindGroup, BindGroupLayout, Buffer, CommandEncoder, Device, Queue, RenderPipeline, SurfaceConfiguration};
+use wgpu::util::DeviceExt;
+
+use crate::camera::Camera;
+use crate::instance::InstanceRaw;
+use crate::model::{ModelVertex, Vertex};
+use crate::texture::Texture;
+
+// Size of the sampling kernel, the most `sample_count` can be
+pub const MAX_SAMPLES: u32 = 64;
+// Width and height of the tiled rotation texture, matches NOISE_SIZE in ssao.wgsl
+const NOISE_SIZE: u32 = 4;
+// Normals and distance from the camera of the prepass
+const GEOMETRY_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+const OCCLUSION_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SsaoSettings {
+    pub enabled: bool,
+    // Size of the hemisphere checked around every point in world units, creases wider than this aren't darkened
+    pub radius: f32,
+    // Exponent applied to the unoccluded share, above 1 darkens creases further
+    pub intensity: f32,
+    // Points checked per pixel, up to MAX_SAMPLES
+    pub sample_count: u32,
+    // Depth difference below which geometry doesn't count as occluding, hides noise on flat surfaces
+    pub bias: f32,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        SsaoSettings {
+            enabled: false,
+            radius: 0.5,
+            intensity: 1.5,
+            sample_count: 16,
+            bias: 0.025,
+        }
+    }
+}
+
+pub struct Context {
+    pub settings: SsaoSettings,
+    uniform: SsaoUniform,
+    buffer: Buffer,
+    layout: BindGroupLayout,
+    noise: Texture,
+    // Written by the prepass
+    geometry_texture: Texture,
+    depth_texture: Texture,
+    // Occlusion before and after the blur, the blurred one is what lighting reads
+    raw_texture: Texture,
+    pub texture: Texture,
+    size: wgpu::Extent3d,
+    pub prepass_bind_group: BindGroup,
+    occlusion_bind_group: BindGroup,
+    blur_bind_group: BindGroup,
+    pub prepass_pipeline: RenderPipeline,
+    occlusion_pipeline: RenderPipeline,
+    blur_pipeline: RenderPipeline,
+}
+
+impl Context {
+    pub fn new(device: &Device, queue: &Queue, config: &SurfaceConfiguration, settings: SsaoSettings) -> Self {
+        let uniform = SsaoUniform::new(kernel());
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("SSAO Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let uniform_entry = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            },
+            count: None,
+        };
+        let prepass_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[uniform_entry],
+            label: Some("ssao_prepass_bind_group_layout"),
+        });
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[uniform_entry, texture_entry(1), texture_entry(2), texture_entry(3)],
+            label: Some("ssao_bind_group_layout"),
+        });
+        let prepass_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &prepass_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+            label: Some("ssao_prepass_bind_group"),
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("SSAO Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("ssao.wgsl").into()),
+        });
+        let prepass_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("SSAO Prepass Pipeline Layout"),
+            bind_group_layouts: &[&prepass_layout],
+            push_constant_ranges: &[],
+        });
+        let prepass_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("SSAO Prepass Pipeline"),
+            layout: Some(&prepass_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_prepass",
+                buffers: &[ModelVertex::desc(), InstanceRaw::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_prepass",
+                targets: &[Some(GEOMETRY_FORMAT.into())],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("SSAO Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = |entry_point| device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("SSAO Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point,
+                targets: &[Some(OCCLUSION_FORMAT.into())],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        let noise = create_noise_texture(device, queue);
+        let geometry_texture = Texture::create_target_texture(device, config.width, config.height, GEOMETRY_FORMAT, "ssao_geometry_texture");
+        let depth_texture = create_depth_texture(device, config);
+        let raw_texture = Texture::create_target_texture(device, config.width, config.height, OCCLUSION_FORMAT, "ssao_raw_texture");
+        let texture = Texture::create_target_texture(device, config.width, config.height, OCCLUSION_FORMAT, "ssao_texture");
+        let occlusion_bind_group = create_bind_group(device, &layout, &buffer, &geometry_texture, &noise, &texture);
+        let blur_bind_group = create_bind_group(device, &layout, &buffer, &geometry_texture, &noise, &raw_texture);
+
+        let mut context = Context {
+            settings,
+            uniform,
+            buffer,
+            layout,
+            noise,
+            geometry_texture,
+            depth_texture,
+            raw_texture,
+            texture,
+            size: wgpu::Extent3d { width: config.width, height: config.height, depth_or_array_layers: 1 },
+            prepass_bind_group,
+            occlusion_bind_group,
+            blur_bind_group,
+            prepass_pipeline,
+            occlusion_pipeline: pipeline("fs_occlusion"),
+            blur_pipeline: pipeline("fs_blur"),
+        };
+        context.set_settings(queue, settings);
+        context
+    }
+
+    // Recreate the targets at the new surface size. The occlusion texture is recreated too, callers
+    // have to rebuild the bind groups reading it
+    pub fn resize(&mut self, device: &Device, queue: &Queue, config: &SurfaceConfiguration) {
+        self.geometry_texture = Texture::create_target_texture(device, config.width, config.height, GEOMETRY_FORMAT, "ssao_geometry_texture");
+        self.depth_texture = create_depth_texture(device, config);
+        self.raw_texture = Texture::create_target_texture(device, config.width, config.height, OCCLUSION_FORMAT, "ssao_raw_texture");
+        self.texture = Texture::create_target_texture(device, config.width, config.height, OCCLUSION_FORMAT, "ssao_texture");
+        self.size = wgpu::Extent3d { width: config.width, height: config.height, depth_or_array_layers: 1 };
+        self.occlusion_bind_group = create_bind_group(device, &self.layout, &self.buffer, &self.geometry_texture, &self.noise, &self.texture);
+        self.blur_bind_group = create_bind_group(device, &self.layout, &self.buffer, &self.geometry_texture, &self.noise, &self.raw_texture);
+        if !self.settings.enabled {
+            self.clear(queue);
+        }
+    }
+
+    pub fn set_settings(&mut self, queue: &Queue, mut settings: SsaoSettings) {
+        settings.radius = settings.radius.max(0.001);
+        settings.intensity = settings.intensity.max(0.0);
+        settings.sample_count = settings.sample_count.clamp(1, MAX_SAMPLES);
+        settings.bias = settings.bias.max(0.0);
+        // Lighting keeps reading the occlusion texture, so it has to stop darkening anything
+        if !settings.enabled {
+            self.clear(queue);
+        }
+        self.settings = settings;
+    }
+
+    // Fill the occlusion texture with "nothing occluded"
+    fn clear(&self, queue: &Queue) {
+        queue.write_texture(
+            self.texture.texture.as_image_copy(),
+            &vec![255; (self.size.width * self.size.height) as usize],
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(self.size.width),
+                rows_per_image: NonZeroU32::new(self.size.height),
+            },
+            self.size,
+        );
+    }
+
+    // Follow the camera, the prepass uses the same jittered projection as the main pass
+    pub fn update(&mut self, queue: &Queue, camera: &Camera) {
+        if !self.settings.enabled {
+            return;
+        }
+        let view = cgmath::Matrix4::look_at_rh(camera.eye, camera.target, camera.up);
+        let proj = cgmath::perspective(cgmath::Deg(camera.fovy), camera.aspect, camera.znear, camera.zfar);
+        self.uniform = SsaoUniform {
+            view_proj: camera.build_view_projection_matrix().into(),
+            view: view.into(),
+            proj: proj.into(),
+            inv_proj: proj.invert().unwrap_or(cgmath::Matrix4::identity()).into(),
+            radius: self.settings.radius,
+            intensity: self.settings.intensity,
+            sample_count: self.settings.sample_count,
+            bias: self.settings.bias,
+            ..self.uniform
+        };
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+
+    // Start the prepass, the caller draws the models into it with `prepass_pipeline` and `prepass_bind_group`
+    pub fn begin_prepass<'a>(&'a self, encoder: &'a mut CommandEncoder) -> wgpu::RenderPass<'a> {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("SSAO Prepass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &self.geometry_texture.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color { r: 0.0, g: 0.0, b: 1.0, a: Texture::FAR_DEPTH }),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        })
+    }
+
+    // Compute the occlusion from the prepass and blur it
+    pub fn draw(&self, encoder: &mut CommandEncoder) {
+        for (target, pipeline, bind_group) in [
+            (&self.raw_texture, &self.occlusion_pipeline, &self.occlusion_bind_group),
+            (&self.texture, &self.blur_pipeline, &self.blur_bind_group),
+        ] {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("SSAO Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &target.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+// `occlusion` is only read while blurring, the occlusion pass binds the blurred texture there as it can't read its own target
+fn create_bind_group(device: &Device, layout: &BindGroupLayout, buffer: &Buffer, geometry: &Texture, noise: &Texture, occlusion: &Texture) -> BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&geometry.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&noise.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&occlusion.view),
+            },
+        ],
+        label: Some("ssao_bind_group"),
+    })
+}
+
+// The prepass always has a single sample, whatever the main pass uses
+fn create_depth_texture(device: &Device, config: &SurfaceConfiguration) -> Texture {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("ssao_depth_texture"),
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: Texture::DEPTH_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+    Texture { texture, view, sampler }
+}
+
+// Random unit rotations around z, encoded into 0..1
+fn create_noise_texture(device: &Device, queue: &Queue) -> Texture {
+    let mut random = Random(0x2545f491);
+    let mut data = Vec::new();
+    for _ in 0..NOISE_SIZE * NOISE_SIZE {
+        let direction = cgmath::Vector2::new(random.next() * 2.0 - 1.0, random.next() * 2.0 - 1.0).normalize();
+        data.extend_from_slice(&[((direction.x * 0.5 + 0.5) * 255.0) as u8, ((direction.y * 0.5 + 0.5) * 255.0) as u8, 128, 255]);
+    }
+
+    let size = wgpu::Extent3d { width: NOISE_SIZE, height: NOISE_SIZE, depth_or_array_layers: 1 };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("ssao_noise_texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+    });
+    queue.write_texture(
+        texture.as_image_copy(),
+        &data,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: NonZeroU32::new(4 * NOISE_SIZE),
+            rows_per_image: NonZeroU32::new(NOISE_SIZE),
+        },
+        size,
+    );
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+    Texture { texture, view, sampler }
+}
+
+// Points in the hemisphere around +z, more of them close to the center where occlusion matters most.
+// The distances are random rather than growing along the kernel, so the first `sample_count` points cover the whole hemisphere
+fn kernel() -> [[f32; 4]; MAX_SAMPLES as usize] {
+    let mut random = Random(0x9e3779b9);
+    let mut kernel = [[0.0; 4]; MAX_SAMPLES as usize];
+    for point in kernel.iter_mut() {
+        let direction = cgmath::Vector3::new(random.next() * 2.0 - 1.0, random.next() * 2.0 - 1.0, random.next()).normalize();
+        let scale = random.next();
+        let position = direction * (0.1 + scale * scale * 0.9);
+        *point = [position.x, position.y, position.z, 0.0];
+    }
+    kernel
+}
+
+// Xorshift, enough for a fixed kernel and noise that stay the same between runs
+struct Random(u32);
+
+impl Random {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct SsaoUniform {
+    pub view_proj: [[f32; 4]; 4],
+    pub view: [[f32; 4]; 4],
+    pub proj: [[f32; 4]; 4],
+    pub inv_proj: [[f32; 4]; 4],
+    pub kernel: [[f32; 4]; MAX_SAMPLES as usize],
+    pub radius: f32,
+    pub intensity: f32,
+    pub sample_count: u32,
+    pub bias: f32,
+}
+
+impl SsaoUniform {
+    pub fn new(kernel: [[f32; 4]; MAX_SAMPLES as usize]) -> Self {
+        let identity = cgmath::Matrix4::identity().into();
+        SsaoUniform {
+            view_proj: identity,
+            view: identity,
+            proj: identity,
+            inv_proj: identity,
+            kernel,
+            radius: 0.5,
+            intensity: 1.0,
+            sample_count: 16,
+            bias: 0.025,
+        }
+    }
+}
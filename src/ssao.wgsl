@@ -0,0 +1,171 @@
+// Vertex shader
+
+// Screen-space ambient occlusion. A prepass stores view space normals and depth, then every pixel
+// checks a hemisphere of points around its surface against that depth, and the result is blurred
+// without bleeding across depth edges
+
+// Matches `SsaoUniform` in ssao.rs
+struct Ssao {
+    // Same as the camera's, so the prepass lines up with the main pass
+    view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+    proj: mat4x4<f32>,
+    inv_proj: mat4x4<f32>,
+    // Points in a hemisphere around +z, denser close to the center
+    kernel: array<vec4<f32>, 64>,
+    radius: f32,
+    intensity: f32,
+    sample_count: u32,
+    bias: f32,
+}
+@group(0) @binding(0)
+var<uniform> ssao: Ssao;
+
+// Depth of pixels without geometry, matches `Texture::FAR_DEPTH`
+let FAR_DEPTH: f32 = 65504.0;
+// Width and height of the tiled noise texture, also the size of the blur that hides it
+let NOISE_SIZE: i32 = 4;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(2) normal: vec3<f32>,
+};
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+    @location(9) normal_matrix_0: vec3<f32>,
+    @location(10) normal_matrix_1: vec3<f32>,
+    @location(11) normal_matrix_2: vec3<f32>,
+}
+
+struct PrepassOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) view_normal: vec3<f32>,
+    @location(1) view_depth: f32,
+}
+
+@vertex
+fn vs_prepass(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> PrepassOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    let world_normal = normal_matrix * model.normal;
+
+    var out: PrepassOutput;
+    out.clip_position = ssao.view_proj * world_position;
+    // The view matrix has no scaling, so it turns normals like any direction
+    out.view_normal = (ssao.view * vec4<f32>(world_normal, 0.0)).xyz;
+    out.view_depth = -(ssao.view * world_position).z;
+    return out;
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let position = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u)) * 2.0 - 1.0;
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(position, 0.0, 1.0);
+    out.uv = position * vec2<f32>(0.5, -0.5) + 0.5;
+    return out;
+}
+
+// Fragment shader
+
+// View space normal in xyz and the distance along the view direction in w
+@group(0) @binding(1)
+var t_geometry: texture_2d<f32>;
+// Random rotations around the normal in xy, tiled over the screen
+@group(0) @binding(2)
+var t_noise: texture_2d<f32>;
+// The unblurred occlusion while blurring
+@group(0) @binding(3)
+var t_occlusion: texture_2d<f32>;
+
+@fragment
+fn fs_prepass(in: PrepassOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(normalize(in.view_normal), in.view_depth);
+}
+
+// Point in view space seen at `uv` at the given distance along the view direction
+fn view_position(uv: vec2<f32>, depth: f32) -> vec3<f32> {
+    let ndc = vec2<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+    let far = ssao.inv_proj * vec4<f32>(ndc, 1.0, 1.0);
+    let ray = far.xyz / far.w;
+    return ray * (depth / -ray.z);
+}
+
+@fragment
+fn fs_occlusion(in: VertexOutput) -> @location(0) vec4<f32> {
+    let size = vec2<i32>(textureDimensions(t_geometry));
+    let texel = vec2<i32>(in.clip_position.xy);
+    let geometry = textureLoad(t_geometry, texel, 0);
+    if (geometry.w >= FAR_DEPTH) {
+        return vec4<f32>(1.0);
+    }
+
+    let position = view_position(in.uv, geometry.w);
+    let normal = normalize(geometry.xyz);
+    // Turn the kernel around the normal by the pixel's random rotation
+    let random = textureLoad(t_noise, texel % vec2<i32>(NOISE_SIZE), 0).xyz * 2.0 - 1.0;
+    let tangent = normalize(random - normal * dot(random, normal));
+    let tbn = mat3x3<f32>(tangent, cross(normal, tangent), normal);
+
+    var occluded = 0.0;
+    for (var i = 0u; i < ssao.sample_count; i = i + 1u) {
+        let kernel_position = position + tbn * ssao.kernel[i].xyz * ssao.radius;
+        let projected = ssao.proj * vec4<f32>(kernel_position, 1.0);
+        let uv = projected.xy / projected.w * vec2<f32>(0.5, -0.5) + 0.5;
+        let sample_texel = clamp(vec2<i32>(uv * vec2<f32>(size)), vec2<i32>(0), size - 1);
+        let surface = -textureLoad(t_geometry, sample_texel, 0).w;
+        // Geometry far in front of the point is a separate object, not a crease
+        let in_range = smoothstep(0.0, 1.0, ssao.radius / abs(position.z - surface));
+        if (surface >= kernel_position.z + ssao.bias) {
+            occluded = occluded + in_range;
+        }
+    }
+    let visibility = 1.0 - occluded / f32(max(ssao.sample_count, 1u));
+    return vec4<f32>(pow(visibility, ssao.intensity), 0.0, 0.0, 1.0);
+}
+
+// Average over the noise tile, leaving out neighbours at a different depth so edges stay sharp
+@fragment
+fn fs_blur(in: VertexOutput) -> @location(0) vec4<f32> {
+    let size = vec2<i32>(textureDimensions(t_geometry));
+    let texel = vec2<i32>(in.clip_position.xy);
+    let depth = textureLoad(t_geometry, texel, 0).w;
+
+    var total = 0.0;
+    var weights = 0.0;
+    for (var y = -NOISE_SIZE / 2; y < NOISE_SIZE / 2; y = y + 1) {
+        for (var x = -NOISE_SIZE / 2; x < NOISE_SIZE / 2; x = x + 1) {
+            let neighbour = clamp(texel + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+            let neighbour_depth = textureLoad(t_geometry, neighbour, 0).w;
+            let weight = max(1.0 - abs(neighbour_depth - depth) / (depth * 0.05), 0.0);
+            total = total + textureLoad(t_occlusion, neighbour, 0).r * weight;
+            weights = weights + weight;
+        }
+    }
+    if (weights <= 0.0) {
+        return vec4<f32>(textureLoad(t_occlusion, texel, 0).r, 0.0, 0.0, 1.0);
+    }
+    return vec4<f32>(total / weights, 0.0, 0.0, 1.0);
+}
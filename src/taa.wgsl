@@ -0,0 +1,73 @@
+// Vertex shader
+
+// Temporal anti-aliasing: blends this frame's jittered image into the history of earlier frames,
+// following the velocity buffer to find where each pixel was last frame
+
+// Matches `TaaUniform` in antialiasing.rs
+struct Taa {
+    // Weight of the current frame in the blend
+    blend: f32,
+    // Set when the history holds nothing usable, such as after a resize
+    reset: u32,
+}
+@group(0) @binding(0)
+var<uniform> taa: Taa;
+@group(0) @binding(1)
+var t_current: texture_2d<f32>;
+@group(0) @binding(2)
+var t_history: texture_2d<f32>;
+@group(0) @binding(3)
+var t_velocity: texture_2d<f32>;
+@group(0) @binding(4)
+var s_linear: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let position = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u)) * 2.0 - 1.0;
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(position, 0.0, 1.0);
+    out.uv = position * vec2<f32>(0.5, -0.5) + 0.5;
+    return out;
+}
+
+// Fragment shader
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(t_current));
+    let current = textureSampleLevel(t_current, s_linear, in.uv, 0.0);
+
+    // History outside the colors around the pixel belongs to something that is no longer there, clamping it
+    // to their range keeps moving and disoccluded objects from leaving trails
+    var minimum = current.rgb;
+    var maximum = current.rgb;
+    for (var y = -1; y <= 1; y = y + 1) {
+        for (var x = -1; x <= 1; x = x + 1) {
+            let neighbor = textureSampleLevel(t_current, s_linear, in.uv + vec2<f32>(f32(x), f32(y)) * texel, 0.0).rgb;
+            minimum = min(minimum, neighbor);
+            maximum = max(maximum, neighbor);
+        }
+    }
+
+    let history_uv = in.uv - textureSampleLevel(t_velocity, s_linear, in.uv, 0.0).xy;
+    if (taa.reset != 0u || any(history_uv < vec2<f32>(0.0)) || any(history_uv > vec2<f32>(1.0))) {
+        return current;
+    }
+    let history = clamp(textureSampleLevel(t_history, s_linear, history_uv, 0.0).rgb, minimum, maximum);
+
+    // Weighting by inverse brightness keeps single very bright samples from flickering
+    let current_weight = taa.blend / (1.0 + luminance(current.rgb));
+    let history_weight = (1.0 - taa.blend) / (1.0 + luminance(history));
+    let color = (current.rgb * current_weight + history * history_weight) / (current_weight + history_weight);
+    return vec4<f32>(color, current.a);
+}
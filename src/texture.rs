@@ -1,9 +1,112 @@
-use std::num::NonZeroU32;
+use std::num::{NonZeroU8, NonZeroU32};
 
 use anyhow::*;
 use crate::CanvasContext;
+use crate::mipmap;
 use image::GenericImageView;
 
+// What a texture shows outside of the 0 to 1 range of texture coordinates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressMode {
+    // Tiles the image, which models with tiled texture coordinates rely on
+    Repeat,
+    // Tiles the image, flipping every other copy so the seams line up
+    MirrorRepeat,
+    // Stretches the outermost texels
+    ClampToEdge,
+}
+
+impl From<AddressMode> for wgpu::AddressMode {
+    fn from(mode: AddressMode) -> Self {
+        match mode {
+            AddressMode::Repeat => wgpu::AddressMode::Repeat,
+            AddressMode::MirrorRepeat => wgpu::AddressMode::MirrorRepeat,
+            AddressMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterMode {
+    // The closest texel, keeps pixel art crisp
+    Nearest,
+    // Blends the closest texels
+    Linear,
+}
+
+impl From<FilterMode> for wgpu::FilterMode {
+    fn from(mode: FilterMode) -> Self {
+        match mode {
+            FilterMode::Nearest => wgpu::FilterMode::Nearest,
+            FilterMode::Linear => wgpu::FilterMode::Linear,
+        }
+    }
+}
+
+// How a texture is read, per axis of the texture coordinates and for drawing it larger or smaller than its size
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SamplerOptions {
+    pub address_mode_u: AddressMode,
+    pub address_mode_v: AddressMode,
+    pub mag_filter: FilterMode,
+    pub min_filter: FilterMode,
+    // Between mip levels, linear along with a linear `min_filter` is trilinear filtering
+    pub mipmap_filter: FilterMode,
+    // Most samples taken along the direction a texture is squeezed in, keeping surfaces seen at a
+    // grazing angle sharp. 1 turns it off, other values are rounded down to a power of two up to 16.
+    // Ignored by backends without anisotropic filtering, and when any of the filters is nearest
+    pub anisotropy: u8,
+}
+
+impl SamplerOptions {
+    fn create_sampler(&self, device: &wgpu::Device) -> wgpu::Sampler {
+        // Anisotropic samplers have to filter linearly in every direction
+        let linear = [self.mag_filter, self.min_filter, self.mipmap_filter].iter().all(|&f| f == FilterMode::Linear);
+        let anisotropy = if linear { self.anisotropy.clamp(1, 16) } else { 1 };
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: self.address_mode_u.into(),
+            address_mode_v: self.address_mode_v.into(),
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: self.mag_filter.into(),
+            min_filter: self.min_filter.into(),
+            mipmap_filter: self.mipmap_filter.into(),
+            anisotropy_clamp: NonZeroU8::new(1 << anisotropy.ilog2()).filter(|clamp| clamp.get() > 1),
+            ..Default::default()
+        })
+    }
+}
+
+impl Default for SamplerOptions {
+    fn default() -> Self {
+        SamplerOptions {
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Linear,
+            anisotropy: 1,
+        }
+    }
+}
+
+// How an image is turned into a texture
+#[derive(Clone, Copy, Debug)]
+pub struct TextureOptions {
+    // Build a full mip chain so the texture doesn't shimmer when it is drawn smaller than its size.
+    // Worth turning off for images that are never seen from afar, to save a third more memory
+    pub mipmaps: bool,
+    pub sampler: SamplerOptions,
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        TextureOptions {
+            mipmaps: true,
+            sampler: SamplerOptions::default(),
+        }
+    }
+}
+
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
@@ -12,9 +115,15 @@ pub struct Texture {
 
 impl Texture {
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float; // 1.
+    // Color values above 1, such as environment lighting
+    pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    // Screen space motion of every pixel since the previous frame, followed by its linear depth
+    pub const VELOCITY_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    // Depth stored for pixels without geometry, the largest half float
+    pub const FAR_DEPTH: f64 = 65504.0;
 
     // Create a new texture to contain the depth information of scene
-    pub fn create_depth_texture(ctx: &mut CanvasContext, label: &str) -> Self {
+    pub fn create_depth_texture(ctx: &mut CanvasContext, sample_count: u32, label: &str) -> Self {
         let size = wgpu::Extent3d {
             width: ctx.config.width,
             height: ctx.config.height,
@@ -24,7 +133,7 @@ impl Texture {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
@@ -51,69 +160,459 @@ impl Texture {
         }
     }
 
-    // Load an image from bytes then generate texture
-    pub fn from_bytes(
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        bytes: &[u8],
-        label: &str,
-    ) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+    // Create an array of square depth textures the scene is rendered into from a light's point of view
+    pub fn create_shadow_texture(device: &wgpu::Device, resolution: u32, layers: u32, label: &str) -> Self {
+        let size = wgpu::Extent3d {
+            width: resolution,
+            height: resolution,
+            depth_or_array_layers: layers,
+        };
+        let desc = wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        };
+        let texture = device.create_texture(&desc);
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
     }
 
-    // Generate texture from image data
-    pub fn from_image(
+    // Create a floating point cube map with a mip chain, rendered into one face and mip level at a time
+    pub fn create_cube_texture(device: &wgpu::Device, size: u32, mip_level_count: u32, label: &str) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 6,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::HDR_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
+
+    // Create a floating point color target that is sampled afterwards, such as a lookup table
+    pub fn create_float_texture(device: &wgpu::Device, width: u32, height: u32, label: &str) -> Self {
+        Self::create_target_texture(device, width, height, Self::HDR_FORMAT, label)
+    }
+
+    // Create a color target in any format that is sampled or copied afterwards
+    pub fn create_target_texture(device: &wgpu::Device, width: u32, height: u32, format: wgpu::TextureFormat, label: &str) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::COPY_DST,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
+
+    // Color target with several samples per pixel, resolved into a single sample texture at the end of a pass
+    pub fn create_multisampled_texture(device: &wgpu::Device, width: u32, height: u32, format: wgpu::TextureFormat, sample_count: u32, label: &str) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
+
+    // Upload a high dynamic range image with a box filtered mip chain. 32 bit floats can't be
+    // filtered on every backend, so shaders read single texels from an explicit level.
+    // Ordinary images hold sRGB colors, they are decoded to linear values first
+    pub fn from_hdr_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>,
     ) -> Result<Self> {
-        let rgba = img.to_rgba8();
-        let dimensions = img.dimensions();
+        let mut level = img.to_rgba32f();
+        if !matches!(img, image::DynamicImage::ImageRgb32F(_) | image::DynamicImage::ImageRgba32F(_)) {
+            level.pixels_mut().for_each(|p| p.0[..3].iter_mut().for_each(|c| *c = srgb_to_linear(*c)));
+        }
+        let (width, height) = level.dimensions();
+        let mip_level_count = width.max(height).ilog2() + 1;
 
-        let size = wgpu::Extent3d {
-            width: dimensions.0,
-            height: dimensions.1,
-            depth_or_array_layers: 1,
-        };
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
-            size,
-            mip_level_count: 1,
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            format: wgpu::TextureFormat::Rgba32Float,
             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
         });
 
+        for mip_level in 0..mip_level_count {
+            let (w, h) = level.dimensions();
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                bytemuck::cast_slice(level.as_raw()),
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(16 * w),
+                    rows_per_image: NonZeroU32::new(h),
+                },
+                wgpu::Extent3d { width: w, height: h, depth_or_array_layers: 1 },
+            );
+
+            // Average each 2x2 block, an odd last row or column is folded into the one before it
+            let (next_w, next_h) = ((w / 2).max(1), (h / 2).max(1));
+            level = image::Rgba32FImage::from_fn(next_w, next_h, |x, y| {
+                let mut sum = [0.0; 4];
+                let mut count = 0.0;
+                for sy in (y * 2)..(y * 2 + 2).min(h) {
+                    for sx in (x * 2)..(x * 2 + 2).min(w) {
+                        let p = level.get_pixel(sx, sy);
+                        sum.iter_mut().zip(p.0).for_each(|(s, c)| *s += c);
+                        count += 1.0;
+                    }
+                }
+                image::Rgba(sum.map(|s| s / count))
+            });
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Ok(Self {
+            texture,
+            view,
+            sampler,
+        })
+    }
+
+    // Build a 3D color lookup table from a strip of square slices laid out left to right, like a
+    // 256x16 image for 16 steps. Red grows to the right within a slice, green downwards and blue
+    // from slice to slice. The colors are display values and stay sRGB encoded
+    pub fn from_lut_image(device: &wgpu::Device, queue: &wgpu::Queue, img: &image::DynamicImage, label: &str) -> Result<Self> {
+        let size = img.height();
+        if size == 0 || img.width() != size * size {
+            bail!("Color lookup table {} is {}x{}, expected a strip of square slices such as 256x16", label, img.width(), img.height());
+        }
+
+        // Reorder the slices into depth layers
+        let rgba = img.to_rgba8();
+        let mut data = Vec::with_capacity((size * size * size * 4) as usize);
+        for blue in 0..size {
+            for green in 0..size {
+                for red in 0..size {
+                    data.extend_from_slice(&rgba.get_pixel(blue * size + red, green).0);
+                }
+            }
+        }
+
+        let extent = wgpu::Extent3d { width: size, height: size, depth_or_array_layers: size };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
         queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &rgba,
+            texture.as_image_copy(),
+            &data,
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: NonZeroU32::new(4 * dimensions.0),
-                rows_per_image: NonZeroU32::new(dimensions.1),
+                bytes_per_row: NonZeroU32::new(4 * size),
+                rows_per_image: NonZeroU32::new(size),
             },
-            size,
+            extent,
         );
-
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
             mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Ok(Self {
+            texture,
+            view,
+            sampler,
+        })
+    }
+
+    // Build a cube map from six square images in +X, -X, +Y, -Y, +Z, -Z order. Ordinary images hold
+    // sRGB colors, they are decoded into the same floating point format environments use
+    pub fn from_cube_images(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[image::DynamicImage; 6],
+        label: &str,
+    ) -> Result<Self> {
+        let size = faces[0].width();
+        if faces.iter().any(|face| face.dimensions() != (size, size)) {
+            bail!("Cube map faces of {} must be square and all the same size", label);
+        }
+
+        let texture = Self::create_cube_texture(device, size, 1, label);
+        for (layer, face) in faces.iter().enumerate() {
+            let pixels = face.to_rgba32f().pixels()
+                .flat_map(|p| [srgb_to_linear(p[0]), srgb_to_linear(p[1]), srgb_to_linear(p[2]), p[3]])
+                .map(f32_to_f16)
+                .collect::<Vec<u16>>();
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d { x: 0, y: 0, z: layer as u32 },
+                },
+                bytemuck::cast_slice(&pixels),
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(8 * size),
+                    rows_per_image: NonZeroU32::new(size),
+                },
+                wgpu::Extent3d { width: size, height: size, depth_or_array_layers: 1 },
+            );
+        }
+        Ok(texture)
+    }
+
+    // Create an offscreen color target matching the context format that can be copied back to the CPU
+    pub fn create_render_texture(ctx: &mut CanvasContext, label: &str) -> Self {
+        let size = wgpu::Extent3d {
+            width: ctx.config.width,
+            height: ctx.config.height,
+            depth_or_array_layers: 1,
+        };
+        let desc = wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: ctx.config.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_SRC,
+        };
+        let texture = ctx.device.create_texture(&desc);
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = ctx.device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
             mipmap_filter: wgpu::FilterMode::Nearest,
             ..Default::default()
         });
 
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
+
+    // Load an image from bytes then generate texture
+    pub fn from_bytes(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bytes: &[u8],
+        label: &str,
+        linear: bool,
+        options: TextureOptions,
+        generator: Option<&mipmap::Generator>,
+    ) -> Result<Self> {
+        let img = image::load_from_memory(bytes)?;
+        Self::from_image(device, queue, &img, Some(label), linear, options, generator)
+    }
+
+    // Generate a 1x1 texture from a linear RGBA color
+    pub fn from_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [f32; 4],
+        label: &str,
+    ) -> Result<Self> {
+        // The texture is sampled as sRGB, so encode the color channels before storing them
+        let encode = |c: f32| (linear_to_srgb(c.clamp(0.0, 1.0)) * 255.0).round() as u8;
+        let pixel = image::Rgba([encode(color[0]), encode(color[1]), encode(color[2]), (color[3].clamp(0.0, 1.0) * 255.0).round() as u8]);
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, pixel));
+        Self::from_image(device, queue, &img, Some(label), false, TextureOptions::default(), None)
+    }
+
+    // Generate a 1x1 normal map pointing straight out of the surface, for materials without one
+    pub fn flat_normal(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        label: &str,
+    ) -> Result<Self> {
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba([128, 128, 255, 255])));
+        Self::from_image(device, queue, &img, Some(label), true, TextureOptions::default(), None)
+    }
+
+    // Generate texture from image data, `linear` is for data such as normal maps that holds values rather than colors.
+    // The mip chain is rendered by `generator` when there is one, and averaged on the CPU otherwise
+    pub fn from_image(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        label: Option<&str>,
+        linear: bool,
+        options: TextureOptions,
+        generator: Option<&mipmap::Generator>,
+    ) -> Result<Self> {
+        let mut level = img.to_rgba8();
+        let (width, height) = level.dimensions();
+        let mip_level_count = if options.mipmaps { width.max(height).max(1).ilog2() + 1 } else { 1 };
+        let format = if linear { wgpu::TextureFormat::Rgba8Unorm } else { wgpu::TextureFormat::Rgba8UnormSrgb };
+
+        let mut usage = wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST;
+        if generator.is_some() && mip_level_count > 1 {
+            usage |= wgpu::TextureUsages::RENDER_ATTACHMENT;
+        }
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage,
+        });
+
+        // Only the first level when the GPU renders the rest
+        let uploaded_levels = if generator.is_some() { 1 } else { mip_level_count };
+        for mip_level in 0..uploaded_levels {
+            let (w, h) = level.dimensions();
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                &level,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(4 * w),
+                    rows_per_image: NonZeroU32::new(h),
+                },
+                wgpu::Extent3d { width: w, height: h, depth_or_array_layers: 1 },
+            );
+            if mip_level + 1 < uploaded_levels {
+                level = downsample(&level, !linear);
+            }
+        }
+        if let Some(generator) = generator.filter(|_| mip_level_count > 1) {
+            generator.generate(device, queue, &texture, format, mip_level_count);
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = options.sampler.create_sampler(device);
+
         Ok(Self {
             texture,
             view,
@@ -121,3 +620,69 @@ impl Texture {
         })
     }
 }
+
+// Half size copy of an image averaging each 2x2 block, an odd last row or column is folded into the
+// one before it. sRGB colors are averaged as linear values so the smaller levels don't darken
+fn downsample(level: &image::RgbaImage, srgb: bool) -> image::RgbaImage {
+    let (w, h) = level.dimensions();
+    let decode = |c: u8, channel: usize| {
+        let c = c as f32 / 255.0;
+        if srgb && channel < 3 { srgb_to_linear(c) } else { c }
+    };
+    let encode = |c: f32, channel: usize| {
+        let c = if srgb && channel < 3 { linear_to_srgb(c) } else { c };
+        (c.clamp(0.0, 1.0) * 255.0).round() as u8
+    };
+    image::RgbaImage::from_fn((w / 2).max(1), (h / 2).max(1), |x, y| {
+        let mut sum = [0.0; 4];
+        let mut count = 0.0;
+        for sy in (y * 2)..(y * 2 + 2).min(h) {
+            for sx in (x * 2)..(x * 2 + 2).min(w) {
+                let p = level.get_pixel(sx, sy);
+                sum.iter_mut().enumerate().for_each(|(channel, s)| *s += decode(p.0[channel], channel));
+                count += 1.0;
+            }
+        }
+        image::Rgba([0, 1, 2, 3].map(|channel| encode(sum[channel] / count, channel)))
+    })
+}
+
+// Decode an image for lighting or the sky. `image::load_from_memory` squeezes Radiance HDR files
+// into 8 bits, so those are read with their own decoder to keep values above 1
+pub fn load_hdr_image(bytes: &[u8]) -> Result<image::DynamicImage> {
+    if image::guess_format(bytes)? != image::ImageFormat::Hdr {
+        return Ok(image::load_from_memory(bytes)?);
+    }
+    let decoder = image::codecs::hdr::HdrDecoder::new(std::io::Cursor::new(bytes))?;
+    let (width, height) = (decoder.metadata().width, decoder.metadata().height);
+    let pixels = decoder.read_image_hdr()?.into_iter().flat_map(|p| p.0).collect();
+    let img = image::Rgb32FImage::from_raw(width, height, pixels).ok_or_else(|| anyhow!("HDR image has an unexpected size"))?;
+    Ok(image::DynamicImage::ImageRgb32F(img))
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+// Half precision bits of a float, rounding towards zero. Values too small for a normal half become 0,
+// NaN stays NaN rather than turning into infinity with the other out of range exponents
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    if value.is_nan() {
+        return sign | 0x7e00;
+    }
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = ((bits >> 13) & 0x3ff) as u16;
+    if exponent <= 0 {
+        sign
+    } else if exponent >= 31 {
+        sign | 0x7c00
+    } else {
+        sign | (exponent as u16) << 10 | mantissa
+    }
+}
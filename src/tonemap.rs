@@ -0,0 +1,314 @@
+use wgpu::{BindGroup, BindGroupLayout, Buffer, CommandEncoder, Device, Queue, RenderPipeline, SurfaceConfiguration, TextureView};
+use wgpu::util::DeviceExt;
+
+use crate::texture::Texture;
+
+// Width and height of the log luminance grid measured for auto exposure, each reduction pass
+// shrinks it by 4 down to a single texel
+const LUMINANCE_SIZE: u32 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tonemapper {
+    // Values above 1.0 are clipped
+    None,
+    Reinhard,
+    Aces,
+    Agx,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TonemapSettings {
+    pub tonemapper: Tonemapper,
+    // Brightness change in stops applied before tonemapping, with auto exposure it offsets the measured exposure
+    pub exposure: f32,
+    // Scale the scene so its average luminance lands on middle grey
+    pub auto_exposure: bool,
+    // Fraction of the way the auto exposure moves towards the current scene brightness each frame, 1.0 follows it instantly
+    pub adaptation_rate: f32,
+    // Range of average scene luminance auto exposure compensates for, darker or brighter scenes stay under or over exposed
+    pub min_luminance: f32,
+    pub max_luminance: f32,
+}
+
+impl Default for TonemapSettings {
+    fn default() -> Self {
+        TonemapSettings {
+            tonemapper: Tonemapper::None,
+            exposure: 0.0,
+            auto_exposure: false,
+            adaptation_rate: 0.05,
+            min_luminance: 0.01,
+            max_luminance: 10.0,
+        }
+    }
+}
+
+pub struct Context {
+    pub settings: TonemapSettings,
+    uniform: TonemapUniform,
+    buffer: Buffer,
+    // The scene is rendered into this before it is tonemapped into the surface
+    pub hdr_texture: Texture,
+    layout: BindGroupLayout,
+    // Log luminance grid followed by its reductions, the last one is a single texel
+    luminance_levels: Vec<Texture>,
+    luminance_bind_group: BindGroup,
+    reduce_bind_groups: Vec<BindGroup>,
+    // Adapted luminance of the previous and the current frame, swapping roles every frame
+    adapted: [Texture; 2],
+    adapt_bind_groups: [BindGroup; 2],
+    bind_groups: [BindGroup; 2],
+    // Which of `adapted` is written this frame
+    frame: usize,
+    // Cleared when auto exposure is switched on, the first frame adapts instantly
+    adapted_once: bool,
+    luminance_pipeline: RenderPipeline,
+    reduce_pipeline: RenderPipeline,
+    adapt_pipeline: RenderPipeline,
+    render_pipeline: RenderPipeline,
+}
+
+impl Context {
+    pub fn new(device: &Device, config: &SurfaceConfiguration, settings: TonemapSettings) -> Self {
+        let uniform = TonemapUniform::default();
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Tonemap Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture_entry(1),
+                texture_entry(2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("tonemap_bind_group_layout"),
+        });
+
+        let hdr_texture = Texture::create_float_texture(device, config.width, config.height, "hdr_texture");
+
+        let mut luminance_levels = Vec::new();
+        let mut size = LUMINANCE_SIZE;
+        loop {
+            luminance_levels.push(Texture::create_float_texture(device, size, size, "luminance_texture"));
+            if size == 1 {
+                break;
+            }
+            size /= 4;
+        }
+        let adapted = [
+            Texture::create_float_texture(device, 1, 1, "adapted_luminance_texture"),
+            Texture::create_float_texture(device, 1, 1, "adapted_luminance_texture"),
+        ];
+
+        let bind_group = |source: &Texture, luminance: &Texture| Self::create_bind_group(device, &layout, &buffer, source, luminance);
+        let luminance_bind_group = bind_group(&hdr_texture, &adapted[0]);
+        let reduce_bind_groups = luminance_levels.windows(2).map(|levels| bind_group(&levels[0], &adapted[0])).collect();
+        let average = luminance_levels.last().unwrap();
+        let adapt_bind_groups = [bind_group(average, &adapted[1]), bind_group(average, &adapted[0])];
+        let bind_groups = [bind_group(&hdr_texture, &adapted[0]), bind_group(&hdr_texture, &adapted[1])];
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Tonemap Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Tonemap Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("tonemap.wgsl").into()),
+        });
+        let pipeline = |entry_point, format: wgpu::TextureFormat| device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Tonemap Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point,
+                targets: &[Some(format.into())],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        let mut context = Context {
+            settings,
+            uniform: TonemapUniform {
+                encode_srgb: !config.format.describe().srgb as u32,
+                ..uniform
+            },
+            buffer,
+            hdr_texture,
+            luminance_levels,
+            luminance_bind_group,
+            reduce_bind_groups,
+            adapted,
+            adapt_bind_groups,
+            bind_groups,
+            frame: 0,
+            adapted_once: false,
+            luminance_pipeline: pipeline("fs_luminance", Texture::HDR_FORMAT),
+            reduce_pipeline: pipeline("fs_reduce", Texture::HDR_FORMAT),
+            adapt_pipeline: pipeline("fs_adapt", Texture::HDR_FORMAT),
+            render_pipeline: pipeline("fs_main", config.format),
+            layout,
+        };
+        context.set_settings(settings);
+        context
+    }
+
+    fn create_bind_group(device: &Device, layout: &BindGroupLayout, buffer: &Buffer, source: &Texture, luminance: &Texture) -> BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&luminance.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&source.sampler),
+                },
+            ],
+            label: Some("tonemap_bind_group"),
+        })
+    }
+
+    // Recreate the HDR target at the new surface size
+    pub fn resize(&mut self, device: &Device, config: &SurfaceConfiguration) {
+        self.hdr_texture = Texture::create_float_texture(device, config.width, config.height, "hdr_texture");
+        let bind_group = |luminance: &Texture| Self::create_bind_group(device, &self.layout, &self.buffer, &self.hdr_texture, luminance);
+        self.luminance_bind_group = bind_group(&self.adapted[0]);
+        self.bind_groups = [bind_group(&self.adapted[0]), bind_group(&self.adapted[1])];
+    }
+
+    pub fn set_settings(&mut self, mut settings: TonemapSettings) {
+        settings.adaptation_rate = settings.adaptation_rate.clamp(0.0, 1.0);
+        settings.min_luminance = settings.min_luminance.max(0.0001);
+        settings.max_luminance = settings.max_luminance.max(settings.min_luminance);
+        if settings.auto_exposure && !self.settings.auto_exposure {
+            self.adapted_once = false;
+        }
+        self.settings = settings;
+    }
+
+    // Pick this frame's adapted luminance texture and upload the settings
+    pub fn update(&mut self, queue: &Queue) {
+        let settings = self.settings;
+        let mut adaptation = settings.adaptation_rate;
+        if settings.auto_exposure {
+            self.frame = 1 - self.frame;
+            if !self.adapted_once {
+                adaptation = 1.0;
+                self.adapted_once = true;
+            }
+        }
+        self.uniform = TonemapUniform {
+            exposure: 2f32.powf(settings.exposure),
+            tonemapper: settings.tonemapper as u32,
+            auto_exposure: settings.auto_exposure as u32,
+            adaptation,
+            min_luminance: settings.min_luminance,
+            max_luminance: settings.max_luminance,
+            ..self.uniform
+        };
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+
+    // Measure the scene brightness if needed, then tonemap the HDR target into `view`
+    pub fn draw(&self, encoder: &mut CommandEncoder, view: &TextureView) {
+        if self.settings.auto_exposure {
+            Self::pass(encoder, &self.luminance_levels[0].view, &self.luminance_pipeline, &self.luminance_bind_group);
+            for (level, bind_group) in self.luminance_levels[1..].iter().zip(&self.reduce_bind_groups) {
+                Self::pass(encoder, &level.view, &self.reduce_pipeline, bind_group);
+            }
+            Self::pass(encoder, &self.adapted[self.frame].view, &self.adapt_pipeline, &self.adapt_bind_groups[self.frame]);
+        }
+        Self::pass(encoder, view, &self.render_pipeline, &self.bind_groups[self.frame]);
+    }
+
+    fn pass(encoder: &mut CommandEncoder, target: &TextureView, pipeline: &RenderPipeline, bind_group: &BindGroup) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Tonemap Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct TonemapUniform {
+    pub exposure: f32,
+    pub tonemapper: u32,
+    pub auto_exposure: u32,
+    pub adaptation: f32,
+    pub min_luminance: f32,
+    pub max_luminance: f32,
+    pub encode_srgb: u32,
+    pub _padding: u32,
+}
+
+impl Default for TonemapUniform {
+    fn default() -> Self {
+        TonemapUniform {
+            exposure: 1.0,
+            tonemapper: Tonemapper::None as u32,
+            auto_exposure: 0,
+            adaptation: 1.0,
+            min_luminance: 0.01,
+            max_luminance: 10.0,
+            encode_srgb: 0,
+            _padding: 0,
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// Vertex shader
+
+// Resolves the HDR scene into the surface, measuring the scene brightness first when auto exposure is on
+
+// Matches `TonemapUniform` in tonemap.rs
+struct Tonemap {
+    // Brightness multiplier, on top of the measured exposure with auto exposure
+    exposure: f32,
+    tonemapper: u32,
+    auto_exposure: u32,
+    // Fraction of the way the adapted luminance moves towards the measured one this frame
+    adaptation: f32,
+    min_luminance: f32,
+    max_luminance: f32,
+    // The surface stores the values as they are, so they have to be sRGB encoded here
+    encode_srgb: u32,
+}
+@group(0) @binding(0)
+var<uniform> tonemap: Tonemap;
+@group(0) @binding(1)
+var t_source: texture_2d<f32>;
+// The adapted luminance while tonemapping, the previous one while adapting
+@group(0) @binding(2)
+var t_luminance: texture_2d<f32>;
+@group(0) @binding(3)
+var s_source: sampler;
+
+// Scene luminance auto exposure maps to middle grey
+let MIDDLE_GREY: f32 = 0.18;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let position = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u)) * 2.0 - 1.0;
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(position, 0.0, 1.0);
+    out.uv = position * vec2<f32>(0.5, -0.5) + 0.5;
+    return out;
+}
+
+// Fragment shader
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Log luminance of a coarse grid over the scene, the average of logs keeps a few bright spots from dominating
+@fragment
+fn fs_luminance(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSampleLevel(t_source, s_source, in.uv, 0.0).rgb;
+    return vec4<f32>(log(max(luminance(color), 0.0001)), 0.0, 0.0, 1.0);
+}
+
+// Average 4x4 texels of the previous level into one
+@fragment
+fn fs_reduce(in: VertexOutput) -> @location(0) vec4<f32> {
+    let base = vec2<i32>(in.clip_position.xy) * 4;
+    var sum = 0.0;
+    for (var y = 0; y < 4; y = y + 1) {
+        for (var x = 0; x < 4; x = x + 1) {
+            sum = sum + textureLoad(t_source, base + vec2<i32>(x, y), 0).r;
+        }
+    }
+    return vec4<f32>(sum / 16.0, 0.0, 0.0, 1.0);
+}
+
+// Move the adapted luminance towards this frame's average, so the exposure changes gradually
+@fragment
+fn fs_adapt(in: VertexOutput) -> @location(0) vec4<f32> {
+    let average = exp(textureLoad(t_source, vec2<i32>(0, 0), 0).r);
+    let previous = textureLoad(t_luminance, vec2<i32>(0, 0), 0).r;
+    return vec4<f32>(mix(previous, average, tonemap.adaptation), 0.0, 0.0, 1.0);
+}
+
+fn reinhard(color: vec3<f32>) -> vec3<f32> {
+    return color / (1.0 + color);
+}
+
+// Stephen Hill's fit of the ACES reference rendering and sRGB output transforms. The matrices are
+// written row by row, so colors are multiplied from the left
+fn aces(color: vec3<f32>) -> vec3<f32> {
+    let input = mat3x3<f32>(
+        vec3<f32>(0.59719, 0.35458, 0.04823),
+        vec3<f32>(0.07600, 0.90834, 0.01566),
+        vec3<f32>(0.02840, 0.13383, 0.83777),
+    );
+    let output = mat3x3<f32>(
+        vec3<f32>(1.60475, -0.53108, -0.07367),
+        vec3<f32>(-0.10208, 1.10813, -0.00605),
+        vec3<f32>(-0.00327, -0.07276, 1.07602),
+    );
+    let v = color * input;
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.4329510) + 0.238081;
+    return clamp((a / b) * output, vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+// Polynomial fit of the AgX base contrast curve
+fn agx_contrast(x: vec3<f32>) -> vec3<f32> {
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+}
+
+// AgX compresses a log encoded range of stops in a slightly desaturated space, which keeps very bright
+// colors from skewing in hue, then returns to linear values for the display
+fn agx(color: vec3<f32>) -> vec3<f32> {
+    let inset = mat3x3<f32>(
+        vec3<f32>(0.842479062253094, 0.0423282422610123, 0.0423756549057051),
+        vec3<f32>(0.0784335999999992, 0.878468636469772, 0.0784336),
+        vec3<f32>(0.0792237451477643, 0.0791661274605434, 0.879142973793104),
+    );
+    let outset = mat3x3<f32>(
+        vec3<f32>(1.19687900512017, -0.0528968517574562, -0.0529716355144438),
+        vec3<f32>(-0.0980208811401368, 1.15190312990417, -0.0980434501171241),
+        vec3<f32>(-0.0990297440797205, -0.0989611768448433, 1.15107367264116),
+    );
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+    let encoded = (clamp(log2(max(inset * color, vec3<f32>(1e-10))), vec3<f32>(min_ev), vec3<f32>(max_ev)) - min_ev) / (max_ev - min_ev);
+    let display = outset * agx_contrast(encoded);
+    return pow(max(display, vec3<f32>(0.0)), vec3<f32>(2.2));
+}
+
+fn encode_srgb(c: vec3<f32>) -> vec3<f32> {
+    return select(1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3<f32>(0.0031308));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let hdr = textureSampleLevel(t_source, s_source, in.uv, 0.0);
+
+    var exposure = tonemap.exposure;
+    if (tonemap.auto_exposure != 0u) {
+        let adapted = clamp(textureLoad(t_luminance, vec2<i32>(0, 0), 0).r, tonemap.min_luminance, tonemap.max_luminance);
+        exposure = exposure * MIDDLE_GREY / adapted;
+    }
+    let exposed = hdr.rgb * exposure;
+
+    // Matches the order of `Tonemapper` in tonemap.rs
+    var color: vec3<f32>;
+    switch (tonemap.tonemapper) {
+        case 1u: { color = reinhard(exposed); }
+        case 2u: { color = aces(exposed); }
+        case 3u: { color = agx(exposed); }
+        default: { color = exposed; }
+    }
+    color = clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
+
+    if (tonemap.encode_srgb != 0u) {
+        color = encode_srgb(color);
+    }
+    return vec4<f32>(color, hdr.a);
+}
@@ -0,0 +1,17 @@
+// Darkens the screen towards its corners. params[0]: intensity, radius and softness
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = scene_color(in.uv);
+    let intensity = post.params[0].x;
+    let radius = post.params[0].y;
+    let softness = post.params[0].z;
+
+    // 0 in the center and 1 in the corners, round regardless of the aspect ratio
+    let aspect = post.resolution.x / post.resolution.y;
+    let offset = (in.uv - 0.5) * vec2<f32>(aspect, 1.0);
+    let edge = length(offset) / length(vec2<f32>(aspect, 1.0) * 0.5);
+
+    let darkening = intensity * smoothstep(radius, radius + softness, edge);
+    return vec4<f32>(color.rgb * (1.0 - darkening), color.a);
+}
@@ -20,7 +20,7 @@ impl App {
 
         let mut world = World::new(&window).await;
 
-        world.add_model("banana.obj", Area3D(10.0, 0.0, 10.0)).await;
+        world.add_model("banana.obj", Area3D(10.0, 0.0, 10.0)).await.unwrap();
 
         event_loop.run(move |event, _, control_flow| {
             match event {
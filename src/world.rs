@@ -1,80 +1,180 @@
-use wgpu::{Surface, SurfaceConfiguration, RenderPipeline, Buffer};
+use wgpu::{Surface, SurfaceConfiguration, RenderPipeline, PipelineLayout, Buffer, Adapter, Device, Queue};
 use wgpu::util::DeviceExt;
 
 use crate::texture;
 use crate::model;
+use crate::mipmap;
 
 use crate::CanvasContext;
 
-use crate::texture::Texture;
+use crate::texture::{Texture, TextureOptions};
 use crate::color::Color;
 
 use crate::model::Vertex;
 use crate::model::Area3D;
+use crate::model::ModelId;
 
 use crate::instance::Instance;
+use crate::instance::InstanceId;
 use crate::instance::InstanceRaw;
-use crate::light::LightUniform;
+use crate::light;
+use crate::light::LightId;
+use crate::light::Light;
+use crate::light::PointLight;
 use crate::LightContext;
+use crate::ShadowContext;
+use crate::shadow::ShadowSettings;
+use crate::EnvironmentContext;
+use crate::FogContext;
+use crate::fog::FogSettings;
+use crate::SkyContext;
+use crate::sky::Sky;
+use crate::TonemapContext;
+use crate::tonemap::TonemapSettings;
+use crate::AntiAliasingContext;
+use crate::antialiasing::AntiAliasing;
+use crate::SsaoContext;
+use crate::ssao::SsaoSettings;
+use crate::BloomContext;
+use crate::bloom::BloomSettings;
+use crate::PostContext;
+use crate::post::{PostProcess, PostProcessId, LutId};
 
 use crate::CameraContext;
 use crate::camera::Camera;
 use crate::camera::CameraUniform;
 use crate::camera::CameraController;
 
-use cgmath::{Rotation3, InnerSpace, Zero};
+use cgmath::Rotation3;
 
 use std::iter;
+use std::num::NonZeroU32;
+
+use anyhow::anyhow;
+use image::RgbaImage;
 
 use winit::event::WindowEvent;
 use winit::window::Window;
+use winit::dpi::PhysicalSize;
 
 pub struct World {
     ctx: CanvasContext,
-    surface: Surface,
-    pub size: winit::dpi::PhysicalSize<u32>,
+    surface: Option<Surface>,
+    pub size: PhysicalSize<u32>,
+    render_pipeline_layout: PipelineLayout,
+    light_pipeline_layout: PipelineLayout,
     render_pipeline: RenderPipeline,
     depth_texture: texture::Texture,
+    // Samples per pixel of the main pass, one of `supported_sample_counts`
+    sample_count: u32,
+    supported_sample_counts: Vec<u32>,
+    // Drawn into instead of the HDR target while MSAA is on, then resolved into it
+    msaa_texture: Option<Texture>,
+    // Screen space motion and linear depth written next to the color by every pipeline in the main pass
+    velocity_texture: Texture,
+    msaa_velocity_texture: Option<Texture>,
     camera: CameraContext,
-    instances: Vec<Instance>,
     instance_buffer: Option<Buffer>,
+    instance_capacity: usize,
+    instances_changed: bool,
     light: LightContext,
+    // The light orbiting the scene in `update`, it is also the one casting shadows
+    main_light: LightId,
+    shadow: ShadowContext,
+    environment: EnvironmentContext,
+    ssao: SsaoContext,
+    fog: FogContext,
+    sky: SkyContext,
+    tonemap: TonemapContext,
+    bloom: BloomContext,
+    antialiasing: AntiAliasingContext,
+    post: PostContext,
 }
 
 impl World {
-    pub async fn add_model(&mut self, path: &str, area: Area3D) {
-        self.ctx.load_model(path, area).await;
-        self.update_instances();
+    // Load a model and spawn its first instance at `area`
+    pub async fn add_model(&mut self, path: &str, area: Area3D) -> anyhow::Result<ModelId> {
+        self.add_model_with_options(path, area, TextureOptions::default()).await
     }
 
-    pub fn update_instances(&mut self) {
-        let instances = self.ctx.models.iter().map(|m| {
-            println!("ADDING INSTANCE");
-            let Area3D(x, y, z) = m.area;
-            let position = cgmath::Vector3 { x, y, z };
+    // Like `add_model`, building the model's textures with `options` where its materials don't
+    // set their own
+    pub async fn add_model_with_options(&mut self, path: &str, area: Area3D, options: TextureOptions) -> anyhow::Result<ModelId> {
+        self.ctx.load_model(path, options).await?;
+        let model = ModelId(self.ctx.models.len() - 1);
+        self.spawn_instance(model, Instance::new(area));
+        Ok(model)
+    }
 
-            let rotation = if position.is_zero() {
-                cgmath::Quaternion::from_axis_angle(
-                    cgmath::Vector3::unit_z(),
-                    cgmath::Deg(0.0),
-                )
-            } else {
-                cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(45.0))
-            };
+    // Add another copy of a loaded model, drawn with its own transform. Returns None for a model
+    // that isn't in this world
+    pub fn spawn_instance(&mut self, model: ModelId, instance: Instance) -> Option<InstanceId> {
+        let id = self.ctx.models.get_mut(model.0)?.spawn(instance);
+        self.instances_changed = true;
+        Some(InstanceId { model, id })
+    }
 
-            Instance { position, rotation }
-        }).collect::<Vec<_>>();
+    // Every instance currently spawned for a model, including the first one from `add_model`
+    pub fn instances(&self, model: ModelId) -> Vec<InstanceId> {
+        self.ctx.models.get(model.0).map_or(Vec::new(), |m| m.instances.keys().map(|id| InstanceId { model, id: *id }).collect())
+    }
 
-        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
-        // Create the instance buffer with our data
-        self.instance_buffer = Some(self.ctx.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Instance Buffer"),
-            contents: bytemuck::cast_slice(&instance_data),
-            usage: wgpu::BufferUsages::VERTEX,
-        }));
+    pub fn instance(&self, id: InstanceId) -> Option<&Instance> {
+        self.ctx.models.get(id.model.0)?.instance(id.id)
+    }
+
+    // Replace the transform of an instance, returns false if it no longer exists
+    pub fn update_instance(&mut self, id: InstanceId, instance: Instance) -> bool {
+        match self.ctx.models.get_mut(id.model.0).and_then(|m| m.instance_mut(id.id)) {
+            Some(i) => {
+                *i = instance;
+                self.instances_changed = true;
+                true
+            }
+            None => false,
+        }
+    }
 
-        println!("BUFFER {:?}", self.instance_buffer);
+    pub fn remove_instance(&mut self, id: InstanceId) -> Option<Instance> {
+        let instance = self.ctx.models.get_mut(id.model.0)?.remove(id.id)?;
+        self.instances_changed = true;
+        Some(instance)
+    }
+
+    // Write every model's instances into the shared instance buffer, each model owning one contiguous range
+    fn update_instances(&mut self) {
+        if !self.instances_changed {
+            return;
+        }
+        self.instances_changed = false;
+
+        let mut instance_data = Vec::new();
+        for model in self.ctx.models.iter_mut() {
+            let start = instance_data.len() as u32;
+            instance_data.extend(model.instances.values().map(|i| i.to_raw()));
+            model.instance_range = start..instance_data.len() as u32;
+        }
+
+        if instance_data.is_empty() {
+            return;
+        }
+
+        match &self.instance_buffer {
+            Some(buffer) if self.instance_capacity >= instance_data.len() => {
+                self.ctx.queue.write_buffer(buffer, 0, bytemuck::cast_slice(&instance_data));
+            }
+            _ => {
+                // Create the instance buffer with our data
+                self.instance_buffer = Some(self.ctx.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Instance Buffer"),
+                    contents: bytemuck::cast_slice(&instance_data),
+                    usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                }));
+                self.instance_capacity = instance_data.len();
+            }
+        }
     }
+
     // Initialize the state
     pub async fn new(window: &Window) -> Self {
         let size = window.inner_size();
@@ -87,7 +187,49 @@ impl World {
             force_fallback_adapter: false,
         }).await.unwrap();
 
-        let (device, queue) = adapter.request_device(
+        let (device, queue) = Self::request_device(&adapter).await.unwrap();
+
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: surface.get_supported_formats(&adapter)[0],
+            width: size.width,
+            height: size.height,
+            present_mode: wgpu::PresentMode::Fifo,
+        };
+        surface.configure(&device, &config);
+
+        let sample_counts = Self::probe_sample_counts(&device).await;
+        Self::build(&adapter, device, queue, config, Some(surface), size, sample_counts)
+    }
+
+    // Initialize the state without a window, rendering only into offscreen textures with
+    // `render_to_image`. Pass `force_fallback_adapter` to pick a software adapter on machines without a GPU.
+    pub async fn new_headless(width: u32, height: u32, force_fallback_adapter: bool) -> anyhow::Result<Self> {
+        let size = PhysicalSize::new(width, height);
+
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let adapter = instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::default(),
+            compatible_surface: None,
+            force_fallback_adapter,
+        }).await.ok_or_else(|| anyhow!("No suitable graphics adapter found"))?;
+
+        let (device, queue) = Self::request_device(&adapter).await?;
+
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+        };
+
+        let sample_counts = Self::probe_sample_counts(&device).await;
+        Ok(Self::build(&adapter, device, queue, config, None, size, sample_counts))
+    }
+
+    async fn request_device(adapter: &Adapter) -> Result<(Device, Queue), wgpu::RequestDeviceError> {
+        adapter.request_device(
             &wgpu::DeviceDescriptor {
                 label: None,
                 features: wgpu::Features::empty(),
@@ -99,17 +241,46 @@ impl World {
             },
             // Some(&std::path::Path::new("trace")), // Trace path
             None,
-        ).await.unwrap();
+        ).await
+    }
 
-        let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: surface.get_supported_formats(&adapter)[0],
-            width: size.width,
-            height: size.height,
-            present_mode: wgpu::PresentMode::Fifo,
-        };
-        surface.configure(&device, &config);
+    // The adapter only reports whether a format can be multisampled at all, not which counts work,
+    // so each count draws an empty pass into the main pass targets and is offered if that validates
+    async fn probe_sample_counts(device: &Device) -> Vec<u32> {
+        let mut counts = vec![1];
+        for count in [2, 4, 8] {
+            device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let target = |format, samples| Texture::create_multisampled_texture(device, 4, 4, format, samples, "sample_count_probe_texture");
+            let (color, velocity, depth) = (target(Texture::HDR_FORMAT, count), target(Texture::VELOCITY_FORMAT, count), target(Texture::DEPTH_FORMAT, count));
+            let (color_resolve, velocity_resolve) = (target(Texture::HDR_FORMAT, 1), target(Texture::VELOCITY_FORMAT, 1));
+
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Sample Count Probe Encoder") });
+            drop(encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Sample Count Probe Pass"),
+                color_attachments: &[
+                    color_attachment(Some(&color), &color_resolve, wgpu::Color::BLACK),
+                    color_attachment(Some(&velocity), &velocity_resolve, wgpu::Color::BLACK),
+                ],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &depth.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            }));
+            // Never submitted, finishing is enough to validate the pass
+            encoder.finish();
+
+            if device.pop_error_scope().await.is_none() {
+                counts.push(count);
+            }
+        }
+        counts
+    }
 
+    fn build(adapter: &Adapter, device: Device, queue: Queue, config: SurfaceConfiguration, surface: Option<Surface>, size: PhysicalSize<u32>, supported_sample_counts: Vec<u32>) -> Self {
         let texture_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[
                 wgpu::BindGroupLayoutEntry {
@@ -128,11 +299,90 @@ impl World {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                // Normal map
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                // Material constants
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // Metallic-roughness, occlusion and emissive maps
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 9,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
             ],
             label: Some("texture_bind_group_layout"),
         });
 
-        let mut ctx = CanvasContext::new(device, queue, texture_bind_group_layout, config);
+        let mipmaps = mipmap::Generator::is_supported(adapter).then(|| mipmap::Generator::new(&device));
+        let anisotropic_filtering = adapter.get_downlevel_capabilities().flags.contains(wgpu::DownlevelFlags::ANISOTROPIC_FILTERING);
+        let mut ctx = CanvasContext::new(device, queue, texture_bind_group_layout, config, mipmaps, anisotropic_filtering);
 
         let camera = Camera {
             eye: (0.0, 5.0, -10.0).into(),
@@ -142,6 +392,7 @@ impl World {
             fovy: 45.0,
             znear: 0.1,
             zfar: 100.0,
+            jitter: cgmath::Vector2::new(0.0, 0.0),
         };
 
         let camera_controller = CameraController::new(0.2);
@@ -154,129 +405,339 @@ impl World {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let camera_bind_group_layout = ctx.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            }],
-            label: Some("camera_bind_group_layout"),
-        });
-
-        let camera_bind_group = ctx.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &camera_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: camera_buffer.as_entire_binding(),
-            }],
-            label: Some("camera_bind_group"),
-        });
-
-        let light_uniform = LightUniform::new(Area3D(2.0, 2.0, 2.0), Color::new(1.0, 1.0, 1.0));
-
-        let light_buffer = ctx.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Light VB"),
-            contents: bytemuck::cast_slice(&[light_uniform]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
+        // The environment maps, ambient occlusion and fog are bound next to the camera
+        let environment = EnvironmentContext::new(&ctx.device, &ctx.queue);
+        let ssao = SsaoContext::new(&ctx.device, &ctx.queue, &ctx.config, SsaoSettings::default());
+        let fog = FogContext::new(&ctx.device, FogSettings::default());
+        let camera_bind_group_layout = &environment.layout;
+        let camera_bind_group = environment.create_bind_group(&ctx.device, &camera_buffer, &ssao.texture, &fog.buffer);
 
         let light_bind_group_layout = ctx.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[wgpu::BindGroupLayoutEntry {
                 binding: 0,
                 visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
+                    ty: light::binding_type(&ctx.device),
                     has_dynamic_offset: false,
                     min_binding_size: None,
                 },
                 count: None,
             }],
-            label: None,
+            label: Some("light_bind_group_layout"),
         });
 
-        let light_bind_group = ctx.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &light_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: light_buffer.as_entire_binding(),
-            }],
-            label: None,
-        });
+        let depth_texture = Texture::create_depth_texture(&mut ctx, 1, "depth_texture");
 
-        let depth_texture = Texture::create_depth_texture(&mut ctx, "depth_texture");
+        let shadow = ShadowContext::new(&ctx.device, ShadowSettings::default());
 
         let render_pipeline_layout =
             ctx.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
                 bind_group_layouts: &[
                     &ctx.layout,
-                    &camera_bind_group_layout,
+                    camera_bind_group_layout,
                     &light_bind_group_layout,
+                    &shadow.layout,
                 ],
                 push_constant_ranges: &[],
             });
 
+        let light_pipeline_layout = ctx.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Light Pipeline Layout"),
+            bind_group_layouts: &[camera_bind_group_layout, &light_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let (render_pipeline, light_render_pipeline) = Self::create_pipelines(&ctx.device, &render_pipeline_layout, &light_pipeline_layout, 1);
+
+        let sky = SkyContext::new(&ctx.device, camera_bind_group_layout, &environment, Texture::HDR_FORMAT, 1);
+        let tonemap = TonemapContext::new(&ctx.device, &ctx.config, TonemapSettings::default());
+        let bloom = BloomContext::new(&ctx.device, &ctx.config, &tonemap.hdr_texture, BloomSettings::default());
+        let velocity_texture = Texture::create_target_texture(&ctx.device, ctx.config.width, ctx.config.height, Texture::VELOCITY_FORMAT, "velocity_texture");
+        let antialiasing = AntiAliasingContext::new(&ctx.device, &ctx.config, &velocity_texture);
+        let post = PostContext::new(&ctx.device, &ctx.queue, &ctx.config);
+
+        let mut light = LightContext::new(&ctx.device, light_bind_group_layout, light_render_pipeline);
+        let main_light = light.add(PointLight::new(Area3D(2.0, 2.0, 2.0), Color::new(1.0, 1.0, 1.0)));
+
+        Self {
+            ctx,
+            surface,
+            size,
+            render_pipeline_layout,
+            light_pipeline_layout,
+            render_pipeline,
+            depth_texture,
+            sample_count: 1,
+            supported_sample_counts,
+            msaa_texture: None,
+            velocity_texture,
+            msaa_velocity_texture: None,
+            camera: CameraContext::new(camera, camera_controller, camera_uniform, camera_buffer, camera_bind_group),
+            instance_buffer: None,
+            instance_capacity: 0,
+            instances_changed: false,
+            light,
+            main_light,
+            shadow,
+            environment,
+            ssao,
+            fog,
+            sky,
+            tonemap,
+            bloom,
+            antialiasing,
+            post,
+        }
+    }
+
+    pub fn resize(&mut self, new_size: PhysicalSize<u32>) {
+        if new_size.width > 0 && new_size.height > 0 {
+            self.size = new_size;
+            self.ctx.config.width = new_size.width.min(8192);
+            self.ctx.config.height = new_size.height.min(8192);
+            if let Some(surface) = &self.surface {
+                surface.configure(&self.ctx.device, &self.ctx.config);
+            }
+            self.velocity_texture = Texture::create_target_texture(&self.ctx.device, self.ctx.config.width, self.ctx.config.height, Texture::VELOCITY_FORMAT, "velocity_texture");
+            self.create_targets();
+            self.ssao.resize(&self.ctx.device, &self.ctx.queue, &self.ctx.config);
+            self.camera.bind_group = self.environment.create_bind_group(&self.ctx.device, &self.camera.buffer, &self.ssao.texture, &self.fog.buffer);
+            self.tonemap.resize(&self.ctx.device, &self.ctx.config);
+            self.bloom.resize(&self.ctx.device, &self.ctx.config, &self.tonemap.hdr_texture);
+            self.antialiasing.resize(&self.ctx.device, &self.ctx.config, &self.velocity_texture);
+            self.post.resize(&self.ctx.device, &self.ctx.queue, &self.ctx.config, &self.velocity_texture);
+        }
+    }
+
+    // Depth and MSAA targets matching the surface size and sample count
+    fn create_targets(&mut self) {
+        self.depth_texture = Texture::create_depth_texture(&mut self.ctx, self.sample_count, "depth_texture");
+        let (width, height) = (self.ctx.config.width, self.ctx.config.height);
+        let multisampled = |format, label| Texture::create_multisampled_texture(&self.ctx.device, width, height, format, self.sample_count, label);
+        self.msaa_texture = (self.sample_count > 1).then(|| multisampled(Texture::HDR_FORMAT, "msaa_texture"));
+        self.msaa_velocity_texture = (self.sample_count > 1).then(|| multisampled(Texture::VELOCITY_FORMAT, "msaa_velocity_texture"));
+    }
+
+    fn create_pipelines(device: &Device, render_layout: &PipelineLayout, light_layout: &PipelineLayout, sample_count: u32) -> (RenderPipeline, RenderPipeline) {
         let render_pipeline = {
             let shader = wgpu::ShaderModuleDescriptor {
                 label: Some("Normal Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
+                source: wgpu::ShaderSource::Wgsl(light::shader_source(include_str!("shader.wgsl"), light::storage_supported(device)).into()),
             };
             create_render_pipeline(
-                &ctx.device,
-                &render_pipeline_layout,
-                ctx.config.format,
+                device,
+                render_layout,
+                Texture::HDR_FORMAT,
                 Some(Texture::DEPTH_FORMAT),
+                sample_count,
                 &[model::ModelVertex::desc(), InstanceRaw::desc()],
                 shader,
             )
         };
 
         let light_render_pipeline = {
-            let layout = ctx.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Light Pipeline Layout"),
-                bind_group_layouts: &[&camera_bind_group_layout, &light_bind_group_layout],
-                push_constant_ranges: &[],
-            });
             let shader = wgpu::ShaderModuleDescriptor {
                 label: Some("Light Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("light.wgsl").into()),
+                source: wgpu::ShaderSource::Wgsl(light::shader_source(include_str!("light.wgsl"), light::storage_supported(device)).into()),
             };
             create_render_pipeline(
-                &ctx.device,
-                &layout,
-                ctx.config.format,
-                Some(texture::Texture::DEPTH_FORMAT),
+                device,
+                light_layout,
+                Texture::HDR_FORMAT,
+                Some(Texture::DEPTH_FORMAT),
+                sample_count,
                 &[model::ModelVertex::desc()],
                 shader,
             )
         };
 
-        Self {
-            ctx,
-            surface,
-            size,
-            render_pipeline,
-            depth_texture,
-            camera: CameraContext::new(camera, camera_controller, camera_uniform, camera_buffer, camera_bind_group),
-            instances: Vec::new(),
-            instance_buffer: None,
-            light: LightContext::new(light_uniform, light_buffer, light_bind_group, light_render_pipeline),
+        (render_pipeline, light_render_pipeline)
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    // MSAA sample counts the adapter can render with, 1 turns MSAA off
+    pub fn supported_sample_counts(&self) -> &[u32] {
+        &self.supported_sample_counts
+    }
+
+    // Switch the main pass to `sample_count` samples per pixel
+    pub fn set_sample_count(&mut self, sample_count: u32) -> anyhow::Result<()> {
+        if !self.supported_sample_counts.contains(&sample_count) {
+            return Err(anyhow!("{}x MSAA is not supported by this adapter, supported sample counts are {:?}", sample_count, self.supported_sample_counts));
+        }
+        if sample_count == self.sample_count {
+            return Ok(());
         }
+
+        self.sample_count = sample_count;
+        let (render_pipeline, light_render_pipeline) = Self::create_pipelines(&self.ctx.device, &self.render_pipeline_layout, &self.light_pipeline_layout, sample_count);
+        self.render_pipeline = render_pipeline;
+        self.light.render_pipeline = light_render_pipeline;
+        self.sky.set_sample_count(&self.ctx.device, sample_count);
+        self.create_targets();
+        Ok(())
     }
 
-    pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
-        if new_size.width > 0 && new_size.height > 0 {
-            self.size = new_size;
-            self.ctx.config.width = new_size.width.min(8192);
-            self.ctx.config.height = new_size.height.min(8192);
-            self.surface.configure(&self.ctx.device, &self.ctx.config);
-            self.depth_texture = Texture::create_depth_texture(&mut self.ctx, "depth_texture");
+    // Move the camera to look from `eye` at `target`
+    pub fn set_camera(&mut self, eye: Area3D, target: Area3D) {
+        let (eye, target) = (eye.position().into(), target.position().into());
+        // A jump rather than a motion, so nothing is reprojected from the old view
+        if eye != self.camera.camera.eye || target != self.camera.camera.target {
+            self.camera.camera.eye = eye;
+            self.camera.camera.target = target;
+            self.camera.previous_view_proj = self.camera.camera.unjittered_view_projection_matrix();
+            self.antialiasing.reset_history();
+        }
+        self.camera.uniform.update_view_proj(&self.camera.camera);
+        self.ctx.queue.write_buffer(&self.camera.buffer, 0, bytemuck::cast_slice(&[self.camera.uniform]));
+    }
+
+    // Replace the main light
+    pub fn set_light(&mut self, light: impl Into<Light>) {
+        let light = light.into();
+        if !self.light.update(self.main_light, light) {
+            self.main_light = self.light.add(light);
+        }
+    }
+
+    pub fn shadow_settings(&self) -> ShadowSettings {
+        self.shadow.settings
+    }
+
+    pub fn set_shadow_settings(&mut self, settings: ShadowSettings) {
+        self.shadow.set_settings(&self.ctx.device, settings);
+    }
+
+    // Light the scene with an equirectangular HDR image instead of the flat ambient term of each light
+    pub async fn set_environment(&mut self, path: &str) -> anyhow::Result<()> {
+        let img = texture::load_hdr_image(&CanvasContext::load_binary(path).await?)?;
+        self.environment.load(&self.ctx.device, &self.ctx.queue, &img)
+    }
+
+    pub fn clear_environment(&mut self) {
+        self.environment.clear(&self.ctx.queue);
+    }
+
+    // Scale the light coming from the environment, 1 by default
+    pub fn set_environment_intensity(&mut self, intensity: f32) {
+        self.environment.set_intensity(&self.ctx.queue, intensity);
+    }
+
+    pub fn sky(&self) -> Sky {
+        self.sky.sky
+    }
+
+    pub fn set_sky(&mut self, sky: Sky) {
+        self.sky.set_sky(&self.ctx.queue, sky);
+    }
+
+    // Load six square images in +X, -X, +Y, -Y, +Z, -Z order and show them as the sky
+    pub async fn set_sky_cube_map(&mut self, faces: [&str; 6]) -> anyhow::Result<()> {
+        let mut images = Vec::with_capacity(6);
+        for face in faces {
+            images.push(image::load_from_memory(&CanvasContext::load_binary(face).await?)?);
         }
+        let images: [image::DynamicImage; 6] = images.try_into().map_err(|_| anyhow!("Expected six cube map faces"))?;
+        self.sky.load_cube_map(&self.ctx.device, &self.ctx.queue, &images, &self.environment)
+    }
+
+    // Load an equirectangular image and show it as the sky, without lighting the scene with it
+    pub async fn set_sky_equirectangular(&mut self, path: &str) -> anyhow::Result<()> {
+        let img = texture::load_hdr_image(&CanvasContext::load_binary(path).await?)?;
+        self.sky.load_equirectangular(&self.ctx.device, &self.ctx.queue, &img, &self.environment)
+    }
+
+    pub fn ssao_settings(&self) -> SsaoSettings {
+        self.ssao.settings
+    }
+
+    // Darken the ambient light in creases and corners, measured from the depth and normals on screen
+    pub fn set_ssao_settings(&mut self, settings: SsaoSettings) {
+        self.ssao.set_settings(&self.ctx.queue, settings);
+    }
+
+    pub fn fog_settings(&self) -> FogSettings {
+        self.fog.settings
+    }
+
+    pub fn set_fog_settings(&mut self, settings: FogSettings) {
+        self.fog.set_settings(settings);
+    }
+
+    // Color behind the scene while the sky is `Sky::None`
+    pub fn set_clear_color(&mut self, color: Color) {
+        self.sky.clear_color = color;
+    }
+
+    pub fn tonemap_settings(&self) -> TonemapSettings {
+        self.tonemap.settings
+    }
+
+    pub fn set_tonemap_settings(&mut self, settings: TonemapSettings) {
+        self.tonemap.set_settings(settings);
+    }
+
+    pub fn bloom_settings(&self) -> BloomSettings {
+        self.bloom.settings
+    }
+
+    // Make emissive materials and light gizmos brighter than the threshold glow
+    pub fn set_bloom_settings(&mut self, settings: BloomSettings) {
+        self.bloom.set_settings(&self.ctx.queue, settings);
+    }
+
+    pub fn anti_aliasing(&self) -> AntiAliasing {
+        self.antialiasing.mode
+    }
+
+    // Pick a post-process anti-aliasing technique, cheaper than MSAA where samples are expensive such as WebGL
+    pub fn set_anti_aliasing(&mut self, mode: AntiAliasing) {
+        self.antialiasing.set_mode(mode);
+    }
+
+    // Append a pass to the post-processing chain, run in the order added after tonemapping
+    pub async fn add_post_process(&mut self, process: PostProcess) -> anyhow::Result<PostProcessId> {
+        self.post.add(&self.ctx.device, process, &self.velocity_texture).await
+    }
+
+    pub fn post_process(&self, id: PostProcessId) -> Option<&PostProcess> {
+        self.post.get(id)
+    }
+
+    // Replace a pass keeping its place in the chain, returns false if it no longer exists
+    pub async fn update_post_process(&mut self, id: PostProcessId, process: PostProcess) -> anyhow::Result<bool> {
+        self.post.update(&self.ctx.device, &self.ctx.queue, id, process, &self.velocity_texture).await
+    }
+
+    pub fn remove_post_process(&mut self, id: PostProcessId) -> Option<PostProcess> {
+        self.post.remove(id)
+    }
+
+    // Load a color grading table for `PostProcess::ColorGrading`, an image of its slices side by side
+    // with red growing right, green growing down and blue growing from slice to slice
+    pub async fn load_color_lut(&mut self, path: &str) -> anyhow::Result<LutId> {
+        let img = image::load_from_memory(&CanvasContext::load_binary(path).await?)?;
+        self.post.load_lut(&self.ctx.device, &self.ctx.queue, &img, path)
+    }
+
+    pub fn add_light(&mut self, light: impl Into<Light>) -> LightId {
+        self.light.add(light)
+    }
+
+    pub fn light(&self, id: LightId) -> Option<&Light> {
+        self.light.get(id)
+    }
+
+    // Replace a light, returns false if it no longer exists
+    pub fn update_light(&mut self, id: LightId, light: impl Into<Light>) -> bool {
+        self.light.update(id, light)
+    }
+
+    pub fn remove_light(&mut self, id: LightId) -> Option<Light> {
+        self.light.remove(id)
     }
 
     pub fn input(&mut self, event: &WindowEvent) -> bool {
@@ -293,65 +754,258 @@ impl World {
             bytemuck::cast_slice(&[self.camera.uniform]),
         );
 
-        let old_position: cgmath::Vector3<_> = self.light.uniform.position.into();
-        self.light.uniform.position = (cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0)) * old_position).into();
-        self.ctx.queue.write_buffer(&self.light.buffer, 0, bytemuck::cast_slice(&[self.light.uniform]));
+        if let Some(mut light) = self.light.get(self.main_light).copied() {
+            let rotation = cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0));
+            let orbit = |area: Area3D| {
+                let v = rotation * cgmath::Vector3::from(area.position());
+                Area3D(v.x, v.y, v.z)
+            };
+            match &mut light {
+                Light::Point(l) => l.position = orbit(l.position),
+                Light::Directional(l) => l.direction = orbit(l.direction),
+                Light::Spot(l) => l.position = orbit(l.position),
+            }
+            self.light.update(self.main_light, light);
+        }
     }
 
-    // Primary render flow
+    // Primary render flow, presenting to the window. The errors are the surface's own, so Lost and
+    // Outdated mean the surface needs reconfiguring. A headless world has no surface to present to
+    // and panics here, `render_to_image` is its entry point
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
+        let Some(surface) = self.surface.as_ref() else {
+            panic!("World::render needs a window surface, headless worlds render with World::render_to_image");
+        };
+        let output = surface.get_current_texture()?;
+        self.prepare();
         let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
         let mut encoder = self.ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {label: Some("Render Encoder")});
 
+        self.draw(&mut encoder, &view);
+
+        self.ctx.queue.submit(iter::once(encoder.finish()));
+        output.present();
+
+        Ok(())
+    }
+
+    // Render the scene into an offscreen texture and read the color target back
+    pub fn render_to_image(&mut self) -> anyhow::Result<RgbaImage> {
+        self.prepare();
+        let target = Texture::create_render_texture(&mut self.ctx, "render_to_image_texture");
+        let (width, height) = (self.ctx.config.width, self.ctx.config.height);
+
+        // Rows copied out of a texture have to be aligned to 256 bytes
+        let unpadded_bytes_per_row = 4 * width;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+
+        let output_buffer = self.ctx.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Render To Image Buffer"),
+            size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self.ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {label: Some("Render To Image Encoder")});
+
+        self.draw(&mut encoder, &target.view);
+
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &target.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &output_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(padded_bytes_per_row),
+                    rows_per_image: NonZeroU32::new(height),
+                },
+            },
+            wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+        );
+
+        self.ctx.queue.submit(iter::once(encoder.finish()));
+
+        let slice = output_buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| { let _ = sender.send(result); });
+        self.ctx.device.poll(wgpu::Maintain::Wait);
+        receiver.recv()??;
+
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
         {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
+            let data = slice.get_mapped_range();
+            for row in data.chunks(padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+            }
+        }
+        output_buffer.unmap();
+
+        // Window surfaces frequently prefer BGRA, the image is always RGBA
+        if matches!(self.ctx.config.format, wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb) {
+            pixels.chunks_mut(4).for_each(|p| p.swap(0, 2));
+        }
+
+        RgbaImage::from_raw(width, height, pixels).ok_or_else(|| anyhow!("Rendered image has an unexpected size"))
+    }
+
+    // Upload everything that changed since the last frame
+    fn prepare(&mut self) {
+        self.update_instances();
+
+        let point_lights = self.shadow.select_point_lights(self.light.iter(), self.main_light, self.camera.camera.eye);
+        self.light.set_shadow_slots(point_lights.clone());
+        self.light.write(&self.ctx.device, &self.ctx.queue, self.main_light);
+
+        // A main point light with a cube map doesn't need the single direction map as well
+        let main_light = self.light.index_of(self.main_light).zip(self.light.get(self.main_light))
+            .filter(|(index, _)| !point_lights.contains(index));
+        self.shadow.update(&self.ctx.queue, main_light, &self.camera.camera);
+        self.shadow.update_point(&self.ctx.queue);
+        self.tonemap.update(&self.ctx.queue);
+        self.fog.update(&self.ctx.queue, self.sky.clear_color);
+
+        // Jitter the camera for temporal anti-aliasing and remember where everything was last frame
+        let camera = &mut self.camera;
+        camera.camera.jitter = self.antialiasing.jitter(self.ctx.config.width, self.ctx.config.height);
+        camera.uniform.update_view_proj(&camera.camera);
+        camera.uniform.prev_view_proj = camera.previous_view_proj.into();
+        camera.previous_view_proj = camera.camera.unjittered_view_projection_matrix();
+        self.ctx.queue.write_buffer(&camera.buffer, 0, bytemuck::cast_slice(&[camera.uniform]));
+        self.antialiasing.update(&self.ctx.queue);
+        self.ssao.update(&self.ctx.queue, &self.camera.camera);
+    }
+
+    // Encode the scene into the HDR target and tonemap it into the given color target
+    fn draw(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        if let Some(buffer) = &self.instance_buffer {
+            for cascade in 0..self.shadow.cascade_count() {
+                let mut shadow_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Shadow Pass"),
+                    color_attachments: &[],
+                    depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                        view: &self.shadow.layer_views[cascade],
+                        depth_ops: Some(wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(1.0),
+                            store: true,
                         }),
-                        store: true,
-                    },
-                })],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
+                        stencil_ops: None,
                     }),
-                    stencil_ops: None,
-                }),
-            });
+                });
+
+                shadow_pass.set_pipeline(&self.shadow.render_pipeline);
+                shadow_pass.set_vertex_buffer(1, buffer.slice(..));
+                self.ctx.models.iter().for_each(|m| m.shadow(&mut shadow_pass, &self.shadow.pass_bind_groups[cascade]));
+            }
 
+            // Six faces for every point light with a shadow slot
+            for layer in 0..self.shadow.point_count() * 6 {
+                let mut shadow_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Point Shadow Pass"),
+                    color_attachments: &[],
+                    depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                        view: &self.shadow.point_layer_views[layer],
+                        depth_ops: Some(wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(1.0),
+                            store: true,
+                        }),
+                        stencil_ops: None,
+                    }),
+                });
+
+                shadow_pass.set_pipeline(&self.shadow.point_render_pipeline);
+                shadow_pass.set_vertex_buffer(1, buffer.slice(..));
+                self.ctx.models.iter().for_each(|m| m.shadow(&mut shadow_pass, &self.shadow.point_pass_bind_groups[layer]));
+            }
+        }
+
+        // Normals and depth for ambient occlusion, drawn like the shadow passes with a single bind group
+        if self.ssao.settings.enabled {
+            let mut prepass = self.ssao.begin_prepass(encoder);
             if let Some(buffer) = &self.instance_buffer {
-                render_pass.set_vertex_buffer(1, buffer.slice(..));
-                render_pass.set_pipeline(&self.light.render_pipeline);
-                self.ctx.models.iter().for_each(|m| m.light(&mut render_pass, &self.camera, &self.light));
-                render_pass.set_pipeline(&self.render_pipeline);
-                self.ctx.models.iter().for_each(|m| m.draw(&mut render_pass, &self.camera, &self.light));
+                prepass.set_pipeline(&self.ssao.prepass_pipeline);
+                prepass.set_vertex_buffer(1, buffer.slice(..));
+                self.ctx.models.iter().for_each(|m| m.shadow(&mut prepass, &self.ssao.prepass_bind_group));
             }
+            drop(prepass);
+            self.ssao.draw(encoder);
         }
 
-        self.ctx.queue.submit(iter::once(encoder.finish()));
-        output.present();
+        // Temporal anti-aliasing resolves the scene into the HDR target afterwards
+        let taa = self.antialiasing.mode == AntiAliasing::Taa;
+        let scene = if taa { &self.antialiasing.current } else { &self.tonemap.hdr_texture };
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render Pass"),
+            color_attachments: &[
+                color_attachment(self.msaa_texture.as_ref(), scene, self.sky.clear_color()),
+                color_attachment(self.msaa_velocity_texture.as_ref(), &self.velocity_texture, wgpu::Color { r: 0.0, g: 0.0, b: Texture::FAR_DEPTH, a: 0.0 }),
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
 
-        Ok(())
+        if !matches!(self.sky.sky, Sky::None) {
+            render_pass.set_pipeline(&self.sky.render_pipeline);
+            render_pass.set_bind_group(0, &self.camera.bind_group, &[]);
+            render_pass.set_bind_group(1, &self.sky.bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        if let Some(buffer) = &self.instance_buffer {
+            render_pass.set_vertex_buffer(1, buffer.slice(..));
+            render_pass.set_pipeline(&self.light.render_pipeline);
+            self.ctx.models.iter().for_each(|m| m.light(&mut render_pass, &self.camera, &self.light));
+            render_pass.set_pipeline(&self.render_pipeline);
+            render_pass.set_bind_group(3, &self.shadow.bind_group, &[]);
+            self.ctx.models.iter().for_each(|m| m.draw(&mut render_pass, &self.camera, &self.light));
+        }
+        drop(render_pass);
+
+        if taa {
+            self.antialiasing.resolve(encoder, &self.tonemap.hdr_texture, self.ctx.config.width, self.ctx.config.height);
+        }
+        self.bloom.draw(encoder, &self.tonemap.hdr_texture);
+        // The post-processing chain starts from its first target when it has passes
+        let output = if self.post.is_empty() { view } else { &self.post.targets[0].view };
+        if self.antialiasing.mode == AntiAliasing::Fxaa {
+            self.tonemap.draw(encoder, &self.antialiasing.ldr_texture.view);
+            self.antialiasing.fxaa(encoder, output);
+        } else {
+            self.tonemap.draw(encoder, output);
+        }
+        self.post.draw(encoder, view);
     }
 }
 
+// With MSAA everything is drawn into the multisampled texture and resolved into the target at the end of the pass
+fn color_attachment<'a>(msaa: Option<&'a Texture>, target: &'a Texture, clear: wgpu::Color) -> Option<wgpu::RenderPassColorAttachment<'a>> {
+    Some(wgpu::RenderPassColorAttachment {
+        view: msaa.map_or(&target.view, |t| &t.view),
+        resolve_target: msaa.map(|_| &target.view),
+        ops: wgpu::Operations {
+            load: wgpu::LoadOp::Clear(clear),
+            store: true,
+        },
+    })
+}
 
 fn create_render_pipeline(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
     color_format: wgpu::TextureFormat,
     depth_format: Option<wgpu::TextureFormat>,
+    sample_count: u32,
     vertex_layouts: &[wgpu::VertexBufferLayout],
     shader: wgpu::ShaderModuleDescriptor,
 ) -> wgpu::RenderPipeline {
@@ -367,14 +1021,15 @@ fn create_render_pipeline(
         fragment: Some(wgpu::FragmentState {
             module: &shader,
             entry_point: "fs_main",
-            targets: &[Some(wgpu::ColorTargetState {
-                format: color_format,
-                blend: Some(wgpu::BlendState {
-                    alpha: wgpu::BlendComponent::REPLACE,
-                    color: wgpu::BlendComponent::REPLACE,
+            targets: &[
+                Some(wgpu::ColorTargetState {
+                    format: color_format,
+                    // Materials with a dissolve below 1 are see-through
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
                 }),
-                write_mask: wgpu::ColorWrites::ALL,
-            })],
+                Some(Texture::VELOCITY_FORMAT.into()),
+            ],
         }),
         primitive: wgpu::PrimitiveState {
             topology: wgpu::PrimitiveTopology::TriangleList,
@@ -393,7 +1048,7 @@ fn create_render_pipeline(
             bias: wgpu::DepthBiasState::default(),
         }),
         multisample: wgpu::MultisampleState {
-            count: 1,
+            count: sample_count,
             mask: !0,
             alpha_to_coverage_enabled: false,
         },
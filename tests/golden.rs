@@ -0,0 +1,738 @@
+// Golden image regression tests for the render pipeline.
+//
+// Each scene is rendered headless with a fixed camera and light, then compared against
+// the reference PNG in `tests/golden`. Run with `UPDATE_GOLDEN=1` to regenerate the
+// references after an intentional change to the shading. On a mismatch the rendered
+// image and a diff image are written to `target/golden`.
+
+use std::path::{Path, PathBuf};
+
+use image::{Rgba, RgbaImage};
+use cgmath::Rotation3;
+use wgpu_3d::{AddressMode, AntiAliasing, Area3D, BloomSettings, Color, DirectionalLight, FogMode, FogSettings, Instance, Light, ModelId, PointLight, PostProcess, ShadowSettings, Sky, SpotLight, SsaoSettings, TextureOptions, Tonemapper, TonemapSettings, World};
+
+const WIDTH: u32 = 256;
+const HEIGHT: u32 = 256;
+
+// Largest per-channel difference that still counts as a matching pixel
+const TOLERANCE: u8 = 8;
+// Fraction of pixels allowed to exceed the tolerance, covers rasterization differences between drivers
+const MAX_MISMATCHED: f32 = 0.005;
+// Fraction of pixels MSAA may change in the `msaa` scene, enough for the edges of its cube alone
+const MAX_MSAA_EDGES: f32 = 0.04;
+
+struct Scene {
+    name: &'static str,
+    model: &'static str,
+    eye: Area3D,
+    target: Area3D,
+    light: Light,
+    // Extra scene setup run after the model is loaded
+    setup: fn(&mut World, ModelId),
+}
+
+// The lit cube most scenes start from, tests override the fields they exercise
+fn cube_scene(name: &'static str) -> Scene {
+    Scene {
+        name,
+        model: "cube.obj",
+        eye: Area3D(3.0, 3.0, -4.0),
+        target: Area3D(0.0, 0.0, 0.0),
+        light: PointLight::new(Area3D(2.0, 3.0, -2.0), Color::new(1.0, 1.0, 1.0)).into(),
+        setup: |_, _| {},
+    }
+}
+
+// Machines without any adapter, not even a software one, fail every test unless they opt out
+// with `WGPU_3D_SKIP_GPU_TESTS`, so a missing GPU can't pass for a green run
+fn create_world(test: &str) -> Option<World> {
+    match pollster::block_on(World::new_headless(WIDTH, HEIGHT, true)) {
+        Ok(world) => Some(world),
+        Err(e) if std::env::var_os("WGPU_3D_SKIP_GPU_TESTS").is_some() => {
+            eprintln!("skipping `{}`: {}", test, e);
+            None
+        }
+        Err(e) => panic!("no adapter for `{}`: {} (set WGPU_3D_SKIP_GPU_TESTS to skip)", test, e),
+    }
+}
+
+fn load(scene: &Scene) -> Option<World> {
+    let mut world = create_world(scene.name)?;
+
+    let model = pollster::block_on(world.add_model(scene.model, Area3D(0.0, 0.0, 0.0))).unwrap();
+    (scene.setup)(&mut world, model);
+    world.set_camera(scene.eye, scene.target);
+    world.set_light(scene.light);
+
+    Some(world)
+}
+
+fn render(scene: &Scene) -> Option<RgbaImage> {
+    Some(load(scene)?.render_to_image().unwrap())
+}
+
+fn diff(expected: &RgbaImage, actual: &RgbaImage) -> (usize, RgbaImage) {
+    let mut mismatched = 0;
+    let image = RgbaImage::from_fn(actual.width(), actual.height(), |x, y| {
+        let (e, a) = (expected.get_pixel(x, y), actual.get_pixel(x, y));
+        let delta = e.0.iter().zip(a.0.iter()).map(|(e, a)| e.abs_diff(*a)).max().unwrap();
+        if delta > TOLERANCE {
+            mismatched += 1;
+            Rgba([255, 0, 255, 255])
+        } else {
+            // Fade matching pixels so the mismatches stand out
+            let Rgba([r, g, b, _]) = *a;
+            Rgba([r / 4, g / 4, b / 4, 255])
+        }
+    });
+    (mismatched, image)
+}
+
+fn output_dir() -> PathBuf {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join("golden");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn check(scene: Scene) {
+    let Some(actual) = render(&scene) else { return };
+    let reference = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(format!("{}.png", scene.name));
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        actual.save(&reference).unwrap();
+        return;
+    }
+
+    let expected = image::open(&reference)
+        .unwrap_or_else(|e| panic!("missing reference {}: {} (run with UPDATE_GOLDEN=1)", reference.display(), e))
+        .to_rgba8();
+    assert_eq!(expected.dimensions(), actual.dimensions(), "reference {} has the wrong size", reference.display());
+
+    let (mismatched, diff_image) = diff(&expected, &actual);
+    let allowed = (MAX_MISMATCHED * (WIDTH * HEIGHT) as f32) as usize;
+    if mismatched > allowed {
+        let dir = output_dir();
+        let actual_path = dir.join(format!("{}-actual.png", scene.name));
+        let diff_path = dir.join(format!("{}-diff.png", scene.name));
+        actual.save(&actual_path).unwrap();
+        diff_image.save(&diff_path).unwrap();
+        panic!(
+            "`{}` differs from its reference in {} pixels (allowed {}), see {} and {}",
+            scene.name,
+            mismatched,
+            allowed,
+            actual_path.display(),
+            diff_path.display(),
+        );
+    }
+}
+
+#[test]
+fn cube() {
+    check(cube_scene("cube"));
+}
+
+// What `World::supported_sample_counts` has to find on the adapter `create_world` picks. wgpu
+// validates multisampling per format rather than per count, so it's every count or none
+fn expected_sample_counts() -> Vec<u32> {
+    use wgpu::TextureFormatFeatureFlags as Flags;
+    let instance = wgpu::Instance::new(wgpu::Backends::all());
+    let Some(adapter) = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+        force_fallback_adapter: true,
+        ..Default::default()
+    })) else { return vec![1] };
+    let color = adapter.get_texture_format_features(wgpu::TextureFormat::Rgba16Float).flags;
+    let depth = adapter.get_texture_format_features(wgpu::TextureFormat::Depth32Float).flags;
+    if color.contains(Flags::MULTISAMPLE | Flags::MULTISAMPLE_RESOLVE) && depth.contains(Flags::MULTISAMPLE) {
+        vec![1, 2, 4, 8]
+    } else {
+        vec![1]
+    }
+}
+
+// Jumping the camera starts temporal anti-aliasing over, so the first frame at the new view
+// matches a world that started there instead of ghosting the old view
+#[test]
+fn taa_camera_jump() {
+    let render = |jump: bool| {
+        let scene = Scene {
+            setup: |world, _| world.set_anti_aliasing(AntiAliasing::Taa),
+            ..cube_scene("taa_camera_jump")
+        };
+        let mut world = load(&scene)?;
+        if jump {
+            world.set_camera(Area3D(-3.0, 3.0, 4.0), Area3D(0.0, 0.0, 0.0));
+            for _ in 0..8 {
+                world.render_to_image().unwrap();
+            }
+        }
+        world.set_camera(scene.eye, scene.target);
+        Some(world.render_to_image().unwrap())
+    };
+    let Some(jumped) = render(true) else { return };
+    let Some(fresh) = render(false) else { return };
+
+    let (mismatched, diff_image) = diff(&fresh, &jumped);
+    if mismatched > (MAX_MISMATCHED * (WIDTH * HEIGHT) as f32) as usize {
+        let diff_path = output_dir().join("taa_camera_jump-diff.png");
+        diff_image.save(&diff_path).unwrap();
+        panic!("the first frame after the jump differs in {} pixels, see {}", mismatched, diff_path.display());
+    }
+}
+
+// Sample counts the adapter can't render with are rejected, every other one renders and survives a resize
+#[test]
+fn msaa_sample_counts() {
+    let expected = expected_sample_counts();
+    let Some(mut world) = create_world("msaa_sample_counts") else { return };
+    pollster::block_on(world.add_model("cube.obj", Area3D(0.0, 0.0, 0.0))).unwrap();
+
+    assert_eq!(world.supported_sample_counts(), expected);
+    for count in (0..=16).filter(|count| !expected.contains(count)) {
+        assert!(world.set_sample_count(count).is_err(), "{}x MSAA was accepted", count);
+    }
+    assert_eq!(world.sample_count(), 1);
+
+    for count in expected {
+        world.set_sample_count(count).unwrap();
+        world.resize(winit::dpi::PhysicalSize::new(WIDTH, HEIGHT));
+        assert_eq!(world.render_to_image().unwrap().dimensions(), (WIDTH, HEIGHT));
+        world.resize(winit::dpi::PhysicalSize::new(WIDTH / 2, HEIGHT));
+        assert_eq!(world.render_to_image().unwrap().dimensions(), (WIDTH / 2, HEIGHT));
+    }
+}
+
+// MSAA only changes the pixels along edges, so every multisampled frame has to differ from the
+// single sample one there and nowhere else. Comparing them needs no reference of its own
+#[test]
+#[ignore = "needs an adapter that can multisample the HDR target, the software one can't"]
+fn msaa() {
+    let Some(mut world) = load(&cube_scene("msaa")) else { return };
+    let counts = world.supported_sample_counts().to_vec();
+    assert!(counts.len() > 1, "the adapter can't multisample the HDR target, supported sample counts are {:?}", counts);
+
+    let single = world.render_to_image().unwrap();
+
+    for count in counts.into_iter().skip(1) {
+        world.set_sample_count(count).unwrap();
+        let multi = world.render_to_image().unwrap();
+
+        let (mismatched, diff_image) = diff(&single, &multi);
+        let dir = output_dir();
+        let diff_path = dir.join(format!("msaa-{}x-diff.png", count));
+        multi.save(dir.join(format!("msaa-{}x-actual.png", count))).unwrap();
+        diff_image.save(&diff_path).unwrap();
+        assert!(mismatched > 0, "{}x MSAA rendered the same image as a single sample", count);
+        assert!(
+            mismatched < (MAX_MSAA_EDGES * (WIDTH * HEIGHT) as f32) as usize,
+            "{}x MSAA changed {} pixels, more than the edges of the cube, see {}",
+            count,
+            mismatched,
+            diff_path.display(),
+        );
+    }
+}
+
+#[test]
+fn fxaa() {
+    check(Scene {
+        setup: |world, _| world.set_anti_aliasing(AntiAliasing::Fxaa),
+        ..cube_scene("fxaa")
+    });
+}
+
+// A still camera, so every jittered frame lines up and the edges settle after a full jitter sequence
+#[test]
+fn taa() {
+    check(Scene {
+        setup: |world, _| {
+            world.set_anti_aliasing(AntiAliasing::Taa);
+            world.set_camera(Area3D(3.0, 3.0, -4.0), Area3D(0.0, 0.0, 0.0));
+            world.set_light(PointLight::new(Area3D(2.0, 3.0, -2.0), Color::new(1.0, 1.0, 1.0)));
+            for _ in 0..15 {
+                world.render_to_image().unwrap();
+            }
+        },
+        ..cube_scene("taa")
+    });
+}
+
+// The gizmos of lights brighter than the threshold glow, the white main light's only sits in the knee
+#[test]
+fn bloom() {
+    check(Scene {
+        light: PointLight::new(Area3D(2.0, 2.0, -1.0), Color::new(1.0, 1.0, 1.0)).into(),
+        setup: |world, _| {
+            world.add_light(PointLight { range: 2.0, ..PointLight::new(Area3D(-1.0, 1.5, -1.5), Color::new(8.0, 3.0, 1.0)) });
+            world.add_light(PointLight { range: 2.0, ..PointLight::new(Area3D(1.5, -0.5, -1.5), Color::new(1.0, 3.0, 8.0)) });
+            world.set_tonemap_settings(TonemapSettings { tonemapper: Tonemapper::Aces, ..Default::default() });
+            world.set_bloom_settings(BloomSettings { enabled: true, ..Default::default() });
+        },
+        ..cube_scene("bloom")
+    });
+}
+
+#[test]
+fn post_processing() {
+    check(Scene {
+        setup: |world, _| {
+            world.set_clear_color(Color::new(0.6, 0.7, 0.9));
+            let lut = pollster::block_on(world.load_color_lut("warm-lut.png")).unwrap();
+            pollster::block_on(world.add_post_process(PostProcess::ColorGrading { lut, intensity: 1.0 })).unwrap();
+            pollster::block_on(world.add_post_process(PostProcess::ChromaticAberration { strength: 0.02 })).unwrap();
+            pollster::block_on(world.add_post_process(PostProcess::Vignette { intensity: 0.8, radius: 0.4, softness: 0.6 })).unwrap();
+        },
+        ..cube_scene("post_processing")
+    });
+}
+
+// Darkens the scene with its depth, shaders that don't build are reported instead of added
+#[test]
+fn custom_post_process() {
+    check(Scene {
+        setup: |world, _| {
+            let broken = PostProcess::Custom { shader: "fn fs_main() {".to_string(), params: [0.0; 8] };
+            assert!(pollster::block_on(world.add_post_process(broken)).is_err());
+
+            let shader = "
+                @fragment
+                fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+                    let color = scene_color(in.uv);
+                    let near = post.params[0].x;
+                    let far = post.params[0].y;
+                    let shade = 1.0 - clamp((scene_depth(in.uv) - near) / (far - near), 0.0, 1.0);
+                    return vec4<f32>(color.rgb * shade, color.a);
+                }
+            ";
+            let params = [4.0, 7.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+            pollster::block_on(world.add_post_process(PostProcess::Custom { shader: shader.to_string(), params })).unwrap();
+        },
+        ..cube_scene("custom_post_process")
+    });
+}
+
+// No texture coordinates, normals or textures, one material points at a file that doesn't exist
+#[test]
+fn untextured() {
+    check(Scene {
+        model: "plain.obj",
+        ..cube_scene("untextured")
+    });
+}
+
+// A material with only `map_Kd` keeps the default colors instead of rendering black
+#[test]
+fn texture_only_material() {
+    check(Scene {
+        model: "texture-only.obj",
+        eye: Area3D(0.0, 8.0, -8.0),
+        light: DirectionalLight::new(Area3D(0.3, -1.0, 0.5), Color::new(1.0, 1.0, 1.0)).into(),
+        ..cube_scene("texture_only_material")
+    });
+}
+
+#[test]
+fn banana() {
+    check(Scene {
+        model: "banana.obj",
+        eye: Area3D(0.0, 2.0, -5.0),
+        target: Area3D(0.0, 1.0, 0.0),
+        light: PointLight::new(Area3D(-2.0, 4.0, -3.0), Color::new(1.0, 1.0, 1.0)).into(),
+        ..cube_scene("banana")
+    });
+}
+
+#[test]
+fn cube_gltf() {
+    check(Scene {
+        model: "cube.gltf",
+        ..cube_scene("cube_gltf")
+    });
+}
+
+// The cube packed into a binary glTF beside a mirrored copy, whose winding is flipped back so
+// its outside faces aren't culled
+#[test]
+fn cube_glb() {
+    check(Scene {
+        model: "cube.glb",
+        eye: Area3D(0.0, 3.0, -5.5),
+        ..cube_scene("cube_glb")
+    });
+}
+
+// Cubes with a base color alpha below 1 in each glTF alpha mode, from the left: opaque, masked
+// above its cutoff, masked below it and so cut out entirely, and blended
+#[test]
+fn gltf_alpha_modes() {
+    check(Scene {
+        model: "alpha-modes.gltf",
+        eye: Area3D(0.0, 2.5, -9.0),
+        light: DirectionalLight::new(Area3D(-0.5, -1.0, 0.5), Color::new(1.0, 1.0, 1.0)).into(),
+        ..cube_scene("gltf_alpha_modes")
+    });
+}
+
+// Plastic, gold, rough metal and an emissive sphere, all shaded by the metallic-roughness path
+#[test]
+fn pbr_spheres() {
+    check(Scene {
+        model: "spheres.gltf",
+        eye: Area3D(0.0, 0.8, -5.5),
+        light: PointLight::new(Area3D(-1.0, 3.0, -3.0), Color::new(1.0, 1.0, 1.0)).into(),
+        ..cube_scene("pbr_spheres")
+    });
+}
+
+// The same spheres lit by an HDR sky with a sun, reflected in the smooth metal
+#[test]
+fn environment_lighting() {
+    check(Scene {
+        model: "spheres.gltf",
+        eye: Area3D(0.0, 0.8, -5.5),
+        light: PointLight::new(Area3D(-1.0, 3.0, -3.0), Color::new(1.0, 1.0, 1.0)).into(),
+        setup: |world, _| {
+            pollster::block_on(world.set_environment("sky.hdr")).unwrap();
+            world.set_sky(Sky::Environment);
+        },
+        ..cube_scene("environment_lighting")
+    });
+}
+
+// A light four times brighter than usual, ACES rolls the highlights off instead of clipping them
+#[test]
+fn tonemapping() {
+    check(Scene {
+        model: "spheres.gltf",
+        eye: Area3D(0.0, 0.8, -5.5),
+        light: PointLight::new(Area3D(-1.0, 3.0, -3.0), Color::new(4.0, 4.0, 4.0)).into(),
+        setup: |world, _| world.set_tonemap_settings(TonemapSettings { tonemapper: Tonemapper::Aces, ..world.tonemap_settings() }),
+        ..cube_scene("tonemapping")
+    });
+}
+
+// A dim light brought back up to a normal brightness by auto exposure
+#[test]
+fn auto_exposure() {
+    check(Scene {
+        model: "spheres.gltf",
+        eye: Area3D(0.0, 0.8, -5.5),
+        light: PointLight::new(Area3D(-1.0, 3.0, -3.0), Color::new(0.1, 0.1, 0.1)).into(),
+        setup: |world, _| world.set_tonemap_settings(TonemapSettings {
+            tonemapper: Tonemapper::Agx,
+            auto_exposure: true,
+            ..world.tonemap_settings()
+        }),
+        ..cube_scene("auto_exposure")
+    });
+}
+
+#[test]
+fn sky_gradient() {
+    check(Scene {
+        eye: Area3D(3.0, 1.0, -4.0),
+        target: Area3D(0.0, 0.5, 0.0),
+        setup: |world, _| world.set_sky(Sky::Gradient {
+            zenith: Color::new(0.1, 0.3, 0.8),
+            horizon: Color::new(0.8, 0.8, 0.9),
+            ground: Color::new(0.2, 0.15, 0.1),
+        }),
+        ..cube_scene("sky_gradient")
+    });
+}
+
+// The faces show the same sky and sun as `sky.hdr`
+#[test]
+fn sky_cube_map() {
+    check(Scene {
+        eye: Area3D(-1.5, 0.5, 5.0),
+        target: Area3D(0.0, 1.5, 0.0),
+        setup: |world, _| {
+            let faces = ["sky-px.png", "sky-nx.png", "sky-py.png", "sky-ny.png", "sky-pz.png", "sky-nz.png"];
+            pollster::block_on(world.set_sky_cube_map(faces)).unwrap();
+        },
+        ..cube_scene("sky_cube_map")
+    });
+}
+
+// The same sky converted from `sky.hdr`, seen from the same place as the cube map faces
+#[test]
+fn sky_equirectangular() {
+    check(Scene {
+        eye: Area3D(-1.5, 0.5, 5.0),
+        target: Area3D(0.0, 1.5, 0.0),
+        setup: |world, _| pollster::block_on(world.set_sky_equirectangular("sky.hdr")).unwrap(),
+        ..cube_scene("sky_equirectangular")
+    });
+}
+
+// A row of cubes fading into the clear color, which the fog picks up when it has no color of its own
+#[test]
+fn fog() {
+    check(Scene {
+        eye: Area3D(3.0, 2.0, -6.0),
+        target: Area3D(0.0, 0.0, 6.0),
+        light: DirectionalLight::new(Area3D(-0.5, -1.0, 0.5), Color::new(1.0, 1.0, 1.0)).into(),
+        setup: |world, model| {
+            for i in 1..8 {
+                world.spawn_instance(model, Instance::new(Area3D(0.0, 0.0, i as f32 * 4.0)));
+            }
+            world.set_clear_color(Color::new(0.6, 0.65, 0.7));
+            world.set_fog_settings(FogSettings { mode: FogMode::Linear, start: 4.0, end: 30.0, ..Default::default() });
+        },
+        ..cube_scene("fog")
+    });
+}
+
+// Thick fog pooling on the ground, the tops of the cubes stick out of it
+#[test]
+fn height_fog() {
+    check(Scene {
+        model: "ground.obj",
+        eye: Area3D(4.0, 5.0, -8.0),
+        light: PointLight::new(Area3D(-3.0, 6.0, -2.0), Color::new(1.0, 1.0, 1.0)).into(),
+        setup: |world, _| {
+            let cube = pollster::block_on(world.add_model("cube.obj", Area3D(0.0, 1.0, 0.0))).unwrap();
+            world.spawn_instance(cube, Instance::new(Area3D(-4.0, 0.0, 4.0)));
+            world.set_fog_settings(FogSettings {
+                mode: FogMode::ExponentialSquared,
+                density: 0.02,
+                height_density: 0.5,
+                height: 0.0,
+                height_falloff: 2.0,
+                color: Some(Color::new(0.8, 0.8, 0.75)),
+                ..Default::default()
+            });
+        },
+        ..cube_scene("height_fog")
+    });
+}
+
+// A finely checkered ground seen at a grazing angle, the far squares blend into grey instead of breaking up
+#[test]
+fn mipmaps() {
+    check(Scene {
+        model: "checker.obj",
+        eye: Area3D(0.0, 2.0, -11.0),
+        light: DirectionalLight::new(Area3D(0.0, -1.0, 0.5), Color::new(1.0, 1.0, 1.0)).into(),
+        ..cube_scene("mipmaps")
+    });
+}
+
+// Tiled walls repeating their texture, clamping it through the MTL `-clamp on` option, and below them
+// a copy loaded with mirrored repeat, which the clamped wall keeps overriding
+#[test]
+fn texture_address_modes() {
+    check(Scene {
+        model: "tiles.obj",
+        eye: Area3D(0.0, 0.0, -12.0),
+        light: DirectionalLight::new(Area3D(0.0, 0.0, 1.0), Color::new(1.0, 1.0, 1.0)).into(),
+        setup: |world, _| {
+            let mut options = TextureOptions::default();
+            options.sampler.address_mode_u = AddressMode::MirrorRepeat;
+            options.sampler.address_mode_v = AddressMode::MirrorRepeat;
+            options.sampler.anisotropy = 16;
+            pollster::block_on(world.add_model_with_options("tiles.obj", Area3D(0.0, -4.5, 0.0), options)).unwrap();
+        },
+        ..cube_scene("texture_address_modes")
+    });
+}
+
+// A headless world has no surface to present to, so `render` can't pass for a lost surface that
+// the caller would try to reconfigure
+#[test]
+fn headless_render() {
+    let Some(mut world) = create_world("headless_render") else { return };
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| world.render()));
+    assert!(result.is_err(), "render returned {:?} on a headless world", result.ok());
+}
+
+// A model handle from another world is rejected instead of indexing past this world's models
+#[test]
+fn spawn_instance_foreign_model() {
+    let foreign = {
+        let Some(mut first) = create_world("spawn_instance_foreign_model") else { return };
+        pollster::block_on(first.add_model("cube.obj", Area3D(0.0, 0.0, 0.0))).unwrap();
+        let model = pollster::block_on(first.add_model("plain.obj", Area3D(0.0, 0.0, 0.0))).unwrap();
+        assert!(first.spawn_instance(model, Instance::new(Area3D(0.0, 0.0, 0.0))).is_some());
+        model
+    };
+
+    let Some(mut second) = create_world("spawn_instance_foreign_model") else { return };
+    pollster::block_on(second.add_model("cube.obj", Area3D(0.0, 0.0, 0.0))).unwrap();
+    assert!(second.spawn_instance(foreign, Instance::new(Area3D(0.0, 0.0, 0.0))).is_none());
+}
+
+#[test]
+fn instances() {
+    check(Scene {
+        eye: Area3D(0.0, 6.0, -9.0),
+        light: PointLight::new(Area3D(0.0, 4.0, -4.0), Color::new(1.0, 1.0, 1.0)).into(),
+        setup: |world, model| {
+            let left = world.spawn_instance(model, Instance::new(Area3D(-3.0, 0.0, 0.0))).unwrap();
+            let right = world.spawn_instance(model, Instance::new(Area3D(3.0, 0.0, 0.0))).unwrap();
+            let removed = world.spawn_instance(model, Instance::new(Area3D(0.0, 0.0, 3.0))).unwrap();
+            assert!(world.remove_instance(removed).is_some());
+            // In spawn order, which is also the order they're drawn in
+            assert_eq!(world.instances(model)[1..], [left, right]);
+
+            let mut instance = *world.instance(left).unwrap();
+            instance.rotation = cgmath::Quaternion::from_angle_y(cgmath::Deg(45.0));
+            instance = instance.with_uniform_scale(0.5);
+            assert!(world.update_instance(left, instance));
+            assert!(!world.update_instance(removed, instance));
+
+            // Squashed vertically to check normals stay correct under non-uniform scale
+            let instance = world.instance(right).unwrap().with_scale(Area3D(1.5, 0.5, 1.5));
+            world.update_instance(right, instance);
+        },
+        ..cube_scene("instances")
+    });
+}
+
+#[test]
+fn point_lights() {
+    check(Scene {
+        light: PointLight::new(Area3D(0.0, 6.0, 0.0), Color::new(1.0, 1.0, 1.0)).into(),
+        setup: |world, _| {
+            world.add_light(PointLight::new(Area3D(3.0, 0.0, -1.0), Color::new(1.0, 0.2, 0.2)));
+            let green = world.add_light(PointLight::new(Area3D(0.0, 0.0, 0.0), Color::new(0.2, 1.0, 0.2)));
+            let removed = world.add_light(PointLight::new(Area3D(0.0, -3.0, 0.0), Color::new(1.0, 1.0, 1.0)));
+            assert!(world.update_light(green, PointLight::new(Area3D(-1.0, 0.0, -3.0), Color::new(0.2, 1.0, 0.2))));
+            assert!(world.remove_light(removed).is_some());
+
+            // Enough short-range lights to outgrow the initial storage buffer
+            for i in 0..24 {
+                let angle = i as f32 / 24.0 * std::f32::consts::TAU;
+                let mut light = PointLight::new(Area3D(angle.cos() * 3.0, -1.5, angle.sin() * 3.0), Color::new(0.1, 0.1, 0.4));
+                light.range = 3.0;
+                world.add_light(light);
+            }
+        },
+        ..cube_scene("point_lights")
+    });
+}
+
+#[test]
+fn directional_and_spot_lights() {
+    check(Scene {
+        light: DirectionalLight::new(Area3D(-1.0, -2.0, 0.5), Color::new(0.6, 0.5, 0.4)).into(),
+        setup: |world, _| {
+            let mut spot = SpotLight::new(Area3D(1.0, 0.0, -4.0), Area3D(-0.2, 0.0, 1.0), Color::new(0.4, 0.6, 1.0));
+            spot.inner_angle = 8.0;
+            spot.outer_angle = 14.0;
+            world.add_light(spot);
+        },
+        ..cube_scene("directional_and_spot_lights")
+    });
+}
+
+#[test]
+fn shadows() {
+    check(Scene {
+        model: "ground.obj",
+        eye: Area3D(4.0, 6.0, -8.0),
+        light: PointLight::new(Area3D(-3.0, 6.0, -2.0), Color::new(1.0, 1.0, 1.0)).into(),
+        setup: |world, _| {
+            pollster::block_on(world.add_model("cube.obj", Area3D(0.0, 1.0, 0.0))).unwrap();
+            // The banana floats above the cube to cast a shadow onto it
+            let banana = pollster::block_on(world.add_model("banana.obj", Area3D(-1.0, 3.0, 0.0))).unwrap();
+            world.spawn_instance(banana, Instance::new(Area3D(2.5, 0.0, 2.0)));
+        },
+        ..cube_scene("shadows")
+    });
+}
+
+// Debugging cascades leaves the single shadow map of a spot light untinted
+#[test]
+fn spot_shadow_cascade_debug() {
+    check(Scene {
+        model: "ground.obj",
+        eye: Area3D(4.0, 6.0, -8.0),
+        light: SpotLight::new(Area3D(-3.0, 6.0, -2.0), Area3D(0.5, -1.0, 0.3), Color::new(1.0, 1.0, 1.0)).into(),
+        setup: |world, _| {
+            pollster::block_on(world.add_model("cube.obj", Area3D(0.0, 1.0, 0.0))).unwrap();
+            world.set_shadow_settings(ShadowSettings { debug_cascades: true, ..world.shadow_settings() });
+        },
+        ..cube_scene("spot_shadow_cascade_debug")
+    });
+}
+
+// Empty shadow maps are clamped to a single texel instead of failing to create the depth textures
+#[test]
+fn shadow_resolution_limits() {
+    let Some(mut world) = create_world("shadow_resolution_limits") else { return };
+    pollster::block_on(world.add_model("cube.obj", Area3D(0.0, 0.0, 0.0))).unwrap();
+
+    world.set_shadow_settings(ShadowSettings { resolution: 0, point_resolution: 0, ..Default::default() });
+    assert_eq!((world.shadow_settings().resolution, world.shadow_settings().point_resolution), (1, 1));
+    world.render_to_image().unwrap();
+}
+
+// Lit by the environment, so the inner corner between the cubes and their bases lose some of their light
+#[test]
+fn ssao() {
+    check(Scene {
+        model: "ground.obj",
+        eye: Area3D(5.0, 4.0, 4.0),
+        target: Area3D(1.0, 0.5, 0.0),
+        light: PointLight::new(Area3D(-3.0, 6.0, -2.0), Color::new(0.2, 0.2, 0.2)).into(),
+        setup: |world, _| {
+            pollster::block_on(world.add_model("cube.obj", Area3D(0.0, 1.0, 0.0))).unwrap();
+            pollster::block_on(world.add_model("cube.obj", Area3D(2.0, 1.0, -1.0))).unwrap();
+            pollster::block_on(world.set_environment("sky.hdr")).unwrap();
+            world.set_shadow_settings(ShadowSettings { enabled: false, ..Default::default() });
+            world.set_ssao_settings(SsaoSettings { enabled: true, intensity: 3.0, ..Default::default() });
+        },
+        ..cube_scene("ssao")
+    });
+}
+
+#[test]
+fn point_shadows() {
+    check(Scene {
+        model: "ground.obj",
+        eye: Area3D(2.0, 3.5, -9.0),
+        light: PointLight { range: 12.0, ..PointLight::new(Area3D(0.0, 4.0, 0.0), Color::new(1.0, 1.0, 1.0)) }.into(),
+        setup: |world, _| {
+            // Cubes on every side of the main light, each one casts a shadow pointing away from it
+            let cube = pollster::block_on(world.add_model("cube.obj", Area3D(3.5, 1.0, 0.0))).unwrap();
+            for position in [Area3D(-3.5, 1.0, 0.0), Area3D(0.0, 1.0, 3.5), Area3D(0.0, 1.0, -3.5)] {
+                world.spawn_instance(cube, Instance::new(position));
+            }
+
+            // A second shadow casting light off to the side
+            world.add_light(PointLight {
+                range: 12.0,
+                cast_shadows: true,
+                ..PointLight::new(Area3D(6.0, 3.0, 6.0), Color::new(0.6, 0.2, 0.2))
+            });
+        },
+        ..cube_scene("point_shadows")
+    });
+}
+
+#[test]
+fn shadow_cascades() {
+    check(Scene {
+        model: "ground.obj",
+        eye: Area3D(0.0, 4.0, -20.0),
+        light: DirectionalLight::new(Area3D(-1.0, -2.0, 1.0), Color::new(1.0, 1.0, 1.0)).into(),
+        setup: |world, ground| {
+            let ground_instance = world.instances(ground)[0];
+            let instance = world.instance(ground_instance).unwrap().with_scale(Area3D(4.0, 1.0, 4.0));
+            world.update_instance(ground_instance, instance);
+
+            // Rows of cubes stretching away from the camera into every cascade
+            let cube = pollster::block_on(world.add_model("cube.obj", Area3D(0.0, 1.0, -12.0))).unwrap();
+            for row in 0..8 {
+                for x in [-6.0, 0.0, 6.0] {
+                    world.spawn_instance(cube, Instance::new(Area3D(x, 1.0, row as f32 * 5.0 - 8.0)));
+                }
+            }
+
+            world.set_shadow_settings(ShadowSettings { debug_cascades: true, ..world.shadow_settings() });
+        },
+        ..cube_scene("shadow_cascades")
+    });
+}